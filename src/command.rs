@@ -1,10 +1,24 @@
 //! Handles construction and execution of scriptable commands.
 
-use std::{path::Path, time::Duration};
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
 
+use anyhow::Context as _;
 use image::RgbImage;
 
-use crate::{grim::take_screenshot, slurp::query_rect, ydotool, Position, Rect};
+use crate::{
+    grim::take_screenshot,
+    matching::{Color, Tolerance},
+    screen::{Adapter, Frame, Geometry},
+    slurp::{query_area, query_rect},
+    tesseract::recognize_text,
+    wlr_randr::{query_outputs, Output},
+    ydotool::{self, MouseButton, MouseClick},
+    Anchored, Position, Rect,
+};
 
 mod serde_img {
     use base64::Engine;
@@ -51,18 +65,880 @@ mod serde_img {
     }
 }
 
+mod serde_click {
+    use serde::{Deserialize as _, Serialize as _};
+
+    use crate::ydotool::MouseClick;
+
+    /// Serialize whether and how to click, using a bool for no click and for a single left click.
+    pub(super) fn serialize<S: serde::ser::Serializer>(
+        click: &Option<MouseClick>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match click {
+            None => serializer.serialize_bool(false),
+            Some(click) if *click == MouseClick::default() => serializer.serialize_bool(true),
+            Some(click) => click.serialize(serializer),
+        }
+    }
+
+    /// Deserialize whether and how to click from either a bool or a mouse click.
+    pub(super) fn deserialize<'de, D: serde::de::Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<MouseClick>, D::Error> {
+        /// The representations of whether and how to click.
+        #[derive(serde::Deserialize)]
+        #[serde(untagged)]
+        enum BoolOrClick {
+            /// Whether to click with the left mouse button.
+            Bool(bool),
+            /// How to click.
+            Click(MouseClick),
+        }
+
+        Ok(match BoolOrClick::deserialize(deserializer)? {
+            BoolOrClick::Bool(true) => Some(MouseClick::default()),
+            BoolOrClick::Bool(false) => None,
+            BoolOrClick::Click(click) => Some(click),
+        })
+    }
+}
+
+/// An image that is expected to appear on the screen.
+#[derive(serde::Serialize, serde::Deserialize)]
+pub(crate) struct ImageTarget {
+    /// The location on the screen where the image was recorded.
+    location: Anchored<Rect>,
+    /// The image that is expected to appear.
+    #[serde(with = "serde_img")]
+    image: RgbImage,
+    /// How much the screen contents may deviate from the image while still matching.
+    #[serde(default)]
+    tolerance: Tolerance,
+    /// The area in which the image is searched for instead of only checking `location`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    search_area: Option<Anchored<Rect>>,
+    /// Areas relative to `location` that are ignored when comparing the image.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    mask: Vec<Rect>,
+}
+
+impl ImageTarget {
+    /// Records a new image target.
+    fn record(recorder: &Recorder) -> anyhow::Result<Option<Self>> {
+        let Some(location) = query_rect(false)? else { return Ok(None) };
+
+        while !dialoguer::Confirm::new()
+            .with_prompt("Is the image presented as it should be?")
+            .interact()?
+        {}
+
+        let Some(image) = take_screenshot(location)? else { return Ok(None) };
+
+        let tolerance = query_tolerance()?;
+
+        let mut mask = Vec::new();
+        while dialoguer::Confirm::new()
+            .with_prompt("Do you want to ignore a part of the image when comparing?")
+            .default(false)
+            .interact()?
+        {
+            let Some(ignored) = query_rect(false)? else { continue };
+            if let Some(ignored) = location.intersection(ignored) {
+                mask.push(Rect {
+                    x: ignored.x - location.x,
+                    y: ignored.y - location.y,
+                    ..ignored
+                });
+            }
+        }
+
+        let search_area = if dialoguer::Confirm::new()
+            .with_prompt("Should the image also be searched for outside of the selected location?")
+            .default(false)
+            .interact()?
+        {
+            println!("select the area to search in or click on an output to search all of it");
+            let Some(area) = query_area()? else { return Ok(None) };
+            if tolerance != Tolerance::EXACT
+                && u64::from(area.width) * u64::from(area.height) > LARGE_SEARCH_AREA
+            {
+                println!("searching an area this large with a tolerance is slow, consider selecting a smaller area");
+            }
+            Some(recorder.anchor(area))
+        } else {
+            None
+        };
+
+        Ok(Some(ImageTarget {
+            location: recorder.anchor(location),
+            image,
+            tolerance,
+            search_area,
+            mask,
+        }))
+    }
+
+    /// Returns the area of the screen that needs to be captured to find the image.
+    fn area(&self) -> Rect {
+        self.search_area.as_ref().unwrap_or(&self.location).value
+    }
+
+    /// Adapts the image target to the current screen.
+    fn adapt(&mut self, adapter: &Adapter) -> anyhow::Result<()> {
+        adapter.relative_rects(&self.location, &mut self.mask)?;
+        adapter.rect(&mut self.location)?;
+        adapter.image(&mut self.image, self.location.value);
+        if let Some(area) = &mut self.search_area {
+            adapter.rect(area)?;
+        }
+
+        Ok(())
+    }
+
+    /// Converts the mask to pixel coordinates of the image.
+    fn pixel_mask(&self) -> Vec<Rect> {
+        let scale_x = f64::from(self.image.width()) / f64::from(self.location.value.width);
+        let scale_y = f64::from(self.image.height()) / f64::from(self.location.value.height);
+
+        self.mask
+            .iter()
+            .map(|rect| Rect {
+                x: (f64::from(rect.x) * scale_x).floor() as u32,
+                y: (f64::from(rect.y) * scale_y).floor() as u32,
+                width: (f64::from(rect.width) * scale_x).ceil() as u32,
+                height: (f64::from(rect.height) * scale_y).ceil() as u32,
+            })
+            .collect()
+    }
+
+    /// Looks for the image in the frame and returns the rectangle where it was found.
+    fn find(&self, frame: &Frame) -> Option<Rect> {
+        let area = self.area();
+        let screen = frame.crop(area)?;
+        let position = self
+            .tolerance
+            .find(&self.image, &self.pixel_mask(), &screen)?;
+
+        Some(frame.to_screen(area, position, self.image.dimensions()))
+    }
+
+    /// Returns a description of the image target for printing.
+    fn describe(&self) -> String {
+        let mut description = format!("image at {}", self.location);
+        if let Some(area) = &self.search_area {
+            description.push_str(&format!(" (searched for in {area})"));
+        }
+
+        description
+    }
+}
+
+/// A color that is expected at a position on the screen.
+#[derive(serde::Serialize, serde::Deserialize)]
+pub(crate) struct ColorTarget {
+    /// The position on the screen where the color is expected.
+    position: Anchored<Position>,
+    /// The expected color.
+    color: Color,
+    /// The maximum difference in any color channel for the color to still match.
+    #[serde(default)]
+    tolerance: u8,
+}
+
+impl ColorTarget {
+    /// Records a new color target by sampling the color at a selected position.
+    fn record(recorder: &Recorder) -> anyhow::Result<Option<Self>> {
+        let Some(rect) = query_rect(true)? else { return Ok(None) };
+        let position = rect.origin();
+
+        let area = Rect {
+            x: position.x,
+            y: position.y,
+            width: 1,
+            height: 1,
+        };
+        let Some(image) = take_screenshot(area)? else { return Ok(None) };
+        let color = Color(*image.get_pixel(0, 0));
+        println!("sampled color {color}");
+
+        let tolerance = dialoguer::Input::<u8>::new()
+            .with_prompt("enter the allowed difference per color channel")
+            .default(0)
+            .interact_text()?;
+
+        Ok(Some(ColorTarget {
+            position: recorder.anchor_position(position),
+            color,
+            tolerance,
+        }))
+    }
+
+    /// Adapts the color target to the current screen.
+    fn adapt(&mut self, adapter: &Adapter) -> anyhow::Result<()> {
+        adapter.position(&mut self.position)
+    }
+
+    /// Returns the area of the screen that needs to be captured to check the color.
+    fn area(&self) -> Rect {
+        Rect {
+            x: self.position.value.x,
+            y: self.position.value.y,
+            width: 1,
+            height: 1,
+        }
+    }
+
+    /// Returns the color at the position in the frame.
+    fn sample(&self, frame: &Frame) -> Option<Color> {
+        let image = frame.crop(self.area())?;
+
+        Some(Color(*image.get_pixel(0, 0)))
+    }
+
+    /// Returns whether the color is present in the frame.
+    fn matches(&self, frame: &Frame) -> bool {
+        let tolerance = Tolerance {
+            channel: self.tolerance,
+            mismatch: 0.0,
+        };
+
+        self.sample(frame)
+            .is_some_and(|Color(found)| tolerance.pixel_matches(self.color.0, found))
+    }
+
+    /// Returns a description of the color target for printing.
+    fn describe(&self) -> String {
+        let mut description = format!(
+            "color #box(width: 0.8em, height: 0.8em, fill: rgb(\"{color}\")) \\{color} at {}",
+            self.position,
+            color = self.color,
+        );
+        if self.tolerance != 0 {
+            description.push_str(&format!(" (tolerance {})", self.tolerance));
+        }
+
+        description
+    }
+}
+
+/// A pattern that recognized text is matched against.
+#[derive(serde::Serialize, serde::Deserialize)]
+pub(crate) enum TextPattern {
+    /// Matches text that contains the given string.
+    Contains(String),
+    /// Matches text that matches the given regular expression.
+    Regex(String),
+}
+
+impl TextPattern {
+    /// Records a new text pattern.
+    fn record() -> anyhow::Result<Self> {
+        let is_regex = dialoguer::Select::new()
+            .with_prompt("how should the text be matched?")
+            .items(&["contains the text", "matches a regular expression"])
+            .default(0)
+            .interact()?
+            == 1;
+
+        loop {
+            let text: String = dialoguer::Input::new()
+                .with_prompt("enter the text to match")
+                .interact_text()?;
+
+            if !is_regex {
+                return Ok(TextPattern::Contains(text));
+            }
+            match regex::Regex::new(&text) {
+                Ok(_) => return Ok(TextPattern::Regex(text)),
+                Err(err) => println!("invalid regular expression: {err}"),
+            }
+        }
+    }
+
+    /// Converts the pattern to a regular expression.
+    fn to_regex(&self) -> anyhow::Result<regex::Regex> {
+        Ok(match self {
+            TextPattern::Contains(text) => regex::Regex::new(&regex::escape(text))?,
+            TextPattern::Regex(regex) => regex::Regex::new(regex)?,
+        })
+    }
+}
+
+impl std::fmt::Display for TextPattern {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TextPattern::Contains(text) => write!(f, "containing {text:?}"),
+            TextPattern::Regex(regex) => write!(f, "matching the regular expression {regex:?}"),
+        }
+    }
+}
+
+/// Describes how the output of a shell command is stored in a variable.
+#[derive(serde::Serialize, serde::Deserialize)]
+pub(crate) struct Capture {
+    /// The name of the variable the output is stored in.
+    variable: String,
+    /// The path of the value to store if the output should be parsed as JSON.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    json_path: Option<String>,
+}
+
+impl Capture {
+    /// Records how the output of a shell command should be stored, if it should be stored.
+    fn record() -> anyhow::Result<Option<Self>> {
+        if !dialoguer::Confirm::new()
+            .with_prompt("Should the output of the command be stored in a variable?")
+            .default(false)
+            .interact()?
+        {
+            return Ok(None);
+        }
+
+        let variable = dialoguer::Input::new()
+            .with_prompt("enter the name of the variable to store the output in")
+            .interact_text()?;
+        let json_path = dialoguer::Input::<String>::new()
+            .with_prompt("enter a path such as `items[0].token` to parse the output as JSON or leave empty to store the trimmed output")
+            .allow_empty(true)
+            .interact_text()?;
+
+        Ok(Some(Capture {
+            variable,
+            json_path: (!json_path.trim().is_empty()).then(|| json_path.trim().to_string()),
+        }))
+    }
+
+    /// Extracts the value to store from the output of the command.
+    fn extract(&self, output: &str) -> anyhow::Result<String> {
+        let Some(path) = &self.json_path else { return Ok(output.trim().to_string()) };
+
+        let json = serde_json::from_str(output).context("the output is not valid JSON")?;
+        Ok(match json_lookup(&json, path)? {
+            serde_json::Value::String(value) => value.clone(),
+            value => value.to_string(),
+        })
+    }
+}
+
+/// Looks up the value at a path such as `items[0].token` in a JSON value.
+fn json_lookup<'a>(
+    value: &'a serde_json::Value,
+    path: &str,
+) -> anyhow::Result<&'a serde_json::Value> {
+    let mut current = value;
+    for segment in path
+        .trim_start_matches('$')
+        .split(['.', '['])
+        .filter(|segment| !segment.is_empty())
+    {
+        let next = match segment.strip_suffix(']') {
+            Some(index) => current.get(index.trim().parse::<usize>()?),
+            None => current.get(segment),
+        };
+        let Some(next) = next else { anyhow::bail!("`{path}` is not present in the JSON value") };
+        current = next;
+    }
+
+    Ok(current)
+}
+
+/// How the value of a variable is compared.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub(crate) enum Comparison {
+    /// The value equals the given value.
+    Equal,
+    /// The value does not equal the given value.
+    NotEqual,
+    /// The value contains the given value.
+    Contains,
+    /// The value matches the given regular expression.
+    Matches,
+    /// The value is a number less than the given number.
+    Less,
+    /// The value is a number greater than the given number.
+    Greater,
+}
+
+impl Comparison {
+    /// All available comparisons.
+    const ALL: &'static [Comparison] = &[
+        Comparison::Equal,
+        Comparison::NotEqual,
+        Comparison::Contains,
+        Comparison::Matches,
+        Comparison::Less,
+        Comparison::Greater,
+    ];
+
+    /// Compares the actual value against the expected value.
+    fn compare(self, actual: &str, expected: &str) -> anyhow::Result<bool> {
+        let number = |value: &str| {
+            value
+                .trim()
+                .parse::<f64>()
+                .with_context(|| format!("`{value}` is not a number"))
+        };
+
+        Ok(match self {
+            Comparison::Equal => actual == expected,
+            Comparison::NotEqual => actual != expected,
+            Comparison::Contains => actual.contains(expected),
+            Comparison::Matches => regex::Regex::new(expected)?.is_match(actual),
+            Comparison::Less => number(actual)? < number(expected)?,
+            Comparison::Greater => number(actual)? > number(expected)?,
+        })
+    }
+}
+
+impl std::fmt::Display for Comparison {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Comparison::Equal => "equals",
+            Comparison::NotEqual => "does not equal",
+            Comparison::Contains => "contains",
+            Comparison::Matches => "matches",
+            Comparison::Less => "is less than",
+            Comparison::Greater => "is greater than",
+        })
+    }
+}
+
+/// A condition on the state of the screen or the chain.
+#[derive(serde::Serialize, serde::Deserialize)]
+pub(crate) enum Condition {
+    /// The image is present on the screen.
+    ImagePresent(ImageTarget),
+    /// The color is present at its position on the screen.
+    ColorPresent(ColorTarget),
+    /// The shell command exits successfully.
+    ShellSucceeds {
+        /// The shell command to run.
+        command: String,
+    },
+    /// The value of a variable compares to the given value.
+    Variable {
+        /// The name of the variable.
+        name: String,
+        /// How the value of the variable is compared.
+        comparison: Comparison,
+        /// The value the variable is compared against.
+        value: String,
+    },
+    /// The given condition does not hold.
+    Not(Box<Condition>),
+}
+
+impl Condition {
+    /// Records a new condition.
+    fn record(recorder: &Recorder) -> anyhow::Result<Option<Self>> {
+        let condition = match dialoguer::Select::new()
+            .with_prompt("select the condition")
+            .items(&[
+                "image is present",
+                "color is present",
+                "shell command succeeds",
+                "variable comparison",
+            ])
+            .default(0)
+            .interact()?
+        {
+            0 => ImageTarget::record(recorder)?.map(Condition::ImagePresent),
+            1 => ColorTarget::record(recorder)?.map(Condition::ColorPresent),
+            2 => {
+                let command = dialoguer::Input::new()
+                    .with_prompt("enter the shell command to execute")
+                    .interact_text()?;
+                Some(Condition::ShellSucceeds { command })
+            }
+            _ => {
+                let name = dialoguer::Input::new()
+                    .with_prompt("enter the name of the variable")
+                    .interact_text()?;
+                let comparison = Comparison::ALL[dialoguer::Select::new()
+                    .with_prompt("select the comparison")
+                    .items(Comparison::ALL)
+                    .default(0)
+                    .interact()?];
+                let value = dialoguer::Input::new()
+                    .with_prompt("enter the value to compare against")
+                    .allow_empty(true)
+                    .interact_text()?;
+                Some(Condition::Variable {
+                    name,
+                    comparison,
+                    value,
+                })
+            }
+        };
+
+        let Some(condition) = condition else { return Ok(None) };
+
+        if dialoguer::Confirm::new()
+            .with_prompt("Should the condition be negated?")
+            .default(false)
+            .interact()?
+        {
+            Ok(Some(Condition::Not(Box::new(condition))))
+        } else {
+            Ok(Some(condition))
+        }
+    }
+
+    /// Adapts the positions and images of the condition to the current screen.
+    fn adapt(&mut self, adapter: &Adapter) -> anyhow::Result<()> {
+        match self {
+            Condition::ImagePresent(target) => target.adapt(adapter),
+            Condition::ColorPresent(target) => target.adapt(adapter),
+            Condition::ShellSucceeds { .. } | Condition::Variable { .. } => Ok(()),
+            Condition::Not(condition) => condition.adapt(adapter),
+        }
+    }
+
+    /// Evaluates whether the condition currently holds.
+    fn evaluate(&self, state: &ExecutionState) -> anyhow::Result<bool> {
+        match self {
+            Condition::ImagePresent(target) => {
+                let Some(frame) = Frame::capture([target.area()])? else { return Ok(false) };
+                Ok(target.find(&frame).is_some())
+            }
+            Condition::ColorPresent(target) => {
+                let Some(frame) = Frame::capture([target.area()])? else { return Ok(false) };
+                Ok(target.matches(&frame))
+            }
+            Condition::ShellSucceeds { command } => Ok(state.shell(command)?.1.status()?.success()),
+            Condition::Variable {
+                name,
+                comparison,
+                value,
+            } => {
+                let Some(actual) = state.variables.get(name) else {
+                    anyhow::bail!("variable `{name}` is not set")
+                };
+                comparison.compare(actual, &state.interpolate(value)?)
+            }
+            Condition::Not(condition) => Ok(!condition.evaluate(state)?),
+        }
+    }
+}
+
+/// The number of pixels of a search area above which searching with a tolerance is slow.
+const LARGE_SEARCH_AREA: u64 = 1_000_000;
+
+/// The number of iterations after which a while loop fails if not specified otherwise.
+const DEFAULT_MAX_ITERATIONS: u32 = 1000;
+
+/// Returns the default maximum number of iterations of a while loop.
+fn default_max_iterations() -> u32 {
+    DEFAULT_MAX_ITERATIONS
+}
+
+/// An image together with the commands to run when it appears.
+#[derive(serde::Serialize, serde::Deserialize)]
+pub(crate) struct ImageBranch {
+    /// The image that is being looked for.
+    #[serde(flatten)]
+    target: ImageTarget,
+    /// The commands to run when the image appears.
+    commands: Vec<Command>,
+}
+
+/// What happens when waiting for the screen times out.
+#[derive(Default, serde::Serialize, serde::Deserialize)]
+pub(crate) enum OnTimeout {
+    /// The run fails with an error.
+    #[default]
+    Fail,
+    /// The command is skipped and the chain continues with the next command.
+    Skip,
+    /// The given commands are run before the chain continues with the next command.
+    Recover(Vec<Command>),
+}
+
+/// Controls how often the screen is checked while waiting.
+#[derive(Debug, Clone, Copy, serde::Serialize, serde::Deserialize)]
+pub(crate) struct Polling {
+    /// The time between the first checks, at least [`MIN_POLL_INTERVAL`].
+    interval: Duration,
+    /// The factor by which the time between checks grows after each unsuccessful check.
+    backoff: f64,
+    /// The maximum time between two checks.
+    max_interval: Duration,
+}
+
+/// The minimum time between two checks of the screen.
+const MIN_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+impl Polling {
+    /// Returns the interval to use before the second check.
+    fn first_interval(&self) -> Duration {
+        self.interval.max(MIN_POLL_INTERVAL)
+    }
+
+    /// Returns the interval to use after an unsuccessful check with the given interval.
+    fn next_interval(&self, interval: Duration) -> Duration {
+        let max_interval = self.max_interval.max(self.first_interval());
+        Duration::try_from_secs_f64(interval.as_secs_f64() * self.backoff.max(1.0))
+            .map_or(max_interval, |interval| interval.min(max_interval))
+            .max(MIN_POLL_INTERVAL)
+    }
+}
+
+impl Default for Polling {
+    fn default() -> Self {
+        Polling {
+            interval: Duration::from_millis(100),
+            backoff: 1.5,
+            max_interval: Duration::from_secs(1),
+        }
+    }
+}
+
+/// Controls how long a command waits for the screen to reach the expected state.
+#[derive(serde::Serialize, serde::Deserialize)]
+pub(crate) struct WaitOptions {
+    /// The maximum time to wait, overriding the default timeout of the chain.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    timeout: Option<Duration>,
+    /// What happens when the timeout is reached.
+    #[serde(default)]
+    on_timeout: OnTimeout,
+}
+
+impl WaitOptions {
+    /// Records new wait options.
+    fn record(recorder: &Recorder) -> anyhow::Result<Self> {
+        let timeout = query_optional_duration(
+            "enter a timeout in seconds or leave empty to use the default of the chain",
+        )?;
+
+        let on_timeout = if timeout.or(recorder.default_timeout).is_some() {
+            match dialoguer::Select::new()
+                .with_prompt("what should happen when the timeout is reached?")
+                .items(&["fail the run", "skip this step", "run recovery commands"])
+                .default(0)
+                .interact()?
+            {
+                1 => OnTimeout::Skip,
+                2 => {
+                    println!("recording the recovery commands, select \"finish\" when done");
+                    OnTimeout::Recover(recorder.record_commands()?)
+                }
+                _ => OnTimeout::Fail,
+            }
+        } else {
+            OnTimeout::Fail
+        };
+
+        Ok(WaitOptions {
+            timeout,
+            on_timeout,
+        })
+    }
+
+    /// Loads the chains called by the recovery commands.
+    fn load_calls(&mut self, dir: &Path, stack: &mut Vec<PathBuf>) -> anyhow::Result<()> {
+        if let OnTimeout::Recover(commands) = &mut self.on_timeout {
+            load_calls(commands, dir, stack)?;
+        }
+
+        Ok(())
+    }
+
+    /// Adapts the recovery commands to the current screen.
+    fn adapt(&mut self, adapter: &Adapter) -> anyhow::Result<()> {
+        if let OnTimeout::Recover(commands) = &mut self.on_timeout {
+            adapt_commands(commands, adapter)?;
+        }
+
+        Ok(())
+    }
+
+    /// Repeatedly captures the given areas and runs `check` on the captured frame until it
+    /// produces a value or the timeout is reached.
+    ///
+    /// Returns `None` if the timeout was reached and handled according to `on_timeout`.
+    ///
+    /// The watchers of the chain are checked on the same frame and take precedence over `check`.
+    fn wait<'a, T>(
+        &'a self,
+        state: &mut ExecutionState<'a>,
+        what: &str,
+        areas: &[Rect],
+        mut check: impl FnMut(&Frame) -> anyhow::Result<Option<T>>,
+    ) -> anyhow::Result<Option<T>> {
+        let timeout = self.timeout.or(state.default_timeout);
+        let start = Instant::now();
+        let mut interval = state.polling.first_interval();
+        let areas = areas
+            .iter()
+            .copied()
+            .chain(state.watcher_areas())
+            .collect::<Vec<_>>();
+
+        loop {
+            if let Some(frame) = Frame::capture(areas.iter().copied())? {
+                if !state.handle_watchers(&frame)? {
+                    if let Some(value) = check(&frame)? {
+                        return Ok(Some(value));
+                    }
+                }
+            }
+
+            let mut sleep = interval;
+            if let Some(timeout) = timeout {
+                let Some(remaining) = timeout.checked_sub(start.elapsed()) else { break };
+                sleep = sleep.min(remaining);
+            }
+            std::thread::sleep(sleep);
+            interval = state.polling.next_interval(interval);
+        }
+
+        let elapsed = start.elapsed();
+        match &self.on_timeout {
+            OnTimeout::Fail => anyhow::bail!("timed out waiting for {what} after {elapsed:.1?}"),
+            OnTimeout::Skip => {
+                println!("timed out waiting for {what} after {elapsed:.1?}, skipping");
+            }
+            OnTimeout::Recover(commands) => {
+                println!("timed out waiting for {what} after {elapsed:.1?}, recovering");
+                state.release_keys()?;
+                execute_commands(commands, state).context("failed to recover from timeout")?;
+            }
+        }
+
+        Ok(None)
+    }
+}
+
 /// A single command in a chain of commands.
 #[derive(serde::Serialize, serde::Deserialize)]
 pub(crate) enum Command {
-    /// Waits until an image is present at the given location.
+    /// Waits until an image is present on the screen.
     WaitForImage {
-        /// The location on the screen where the image should appear.
-        location: Rect,
         /// The image that is being waited for.
-        #[serde(with = "serde_img")]
-        image: RgbImage,
-        /// Whether the image should be clicked after it appears.
-        click: bool,
+        #[serde(flatten)]
+        target: ImageTarget,
+        /// How the image should be clicked after it appears, if it should be clicked.
+        #[serde(with = "serde_click")]
+        click: Option<MouseClick>,
+        /// How long to wait for the image.
+        #[serde(flatten)]
+        wait: WaitOptions,
+    },
+    /// Waits until an image is no longer present on the screen.
+    WaitForImageGone {
+        /// The image that should disappear.
+        #[serde(flatten)]
+        target: ImageTarget,
+        /// How long to wait for the image to disappear.
+        #[serde(flatten)]
+        wait: WaitOptions,
+    },
+    /// Waits until any of the given images is present on the screen and then runs the commands
+    /// associated with the first one that appeared.
+    WaitForAny {
+        /// The images that are being waited for.
+        branches: Vec<ImageBranch>,
+        /// How long to wait for any of the images.
+        #[serde(flatten)]
+        wait: WaitOptions,
+    },
+    /// Waits until a color is present at a position on the screen.
+    WaitForColor {
+        /// The color that is being waited for.
+        #[serde(flatten)]
+        target: ColorTarget,
+        /// How long to wait for the color.
+        #[serde(flatten)]
+        wait: WaitOptions,
+    },
+    /// Fails the run if a color is not present at a position on the screen.
+    AssertColor {
+        /// The color that is expected.
+        #[serde(flatten)]
+        target: ColorTarget,
+    },
+    /// Waits until text matching a pattern is recognized in an area of the screen.
+    WaitForText {
+        /// The area of the screen where the text is recognized.
+        location: Anchored<Rect>,
+        /// The pattern the recognized text must match.
+        pattern: TextPattern,
+        /// How long to wait for the text.
+        #[serde(flatten)]
+        wait: WaitOptions,
+    },
+    /// Recognizes the text in an area of the screen and stores it in a variable.
+    ExtractText {
+        /// The area of the screen where the text is recognized.
+        location: Anchored<Rect>,
+        /// The name of the variable the text is stored in.
+        variable: String,
+    },
+    /// Runs one of two chains of commands depending on whether a condition holds.
+    If {
+        /// The condition that decides which commands are run.
+        condition: Condition,
+        /// The commands to run if the condition holds.
+        then: Vec<Command>,
+        /// The commands to run if the condition does not hold.
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        r#else: Vec<Command>,
+    },
+    /// Runs a chain of commands a fixed number of times.
+    Repeat {
+        /// How often the commands are run.
+        count: u32,
+        /// The commands to run.
+        body: Vec<Command>,
+    },
+    /// Runs a chain of commands as long as a condition holds.
+    While {
+        /// The condition that is checked before each iteration.
+        condition: Condition,
+        /// The commands to run.
+        body: Vec<Command>,
+        /// The number of iterations after which the run fails if the condition still holds.
+        #[serde(default = "default_max_iterations")]
+        max_iterations: u32,
+    },
+    /// Runs a chain of commands again when it fails.
+    Retry {
+        /// How often the commands are attempted at most, zero is treated as one.
+        attempts: u32,
+        /// How long to wait before the next attempt.
+        #[serde(default)]
+        delay: Duration,
+        /// The commands to attempt.
+        body: Vec<Command>,
+        /// The commands to run after a failed attempt before the next attempt.
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        on_failure: Vec<Command>,
+    },
+    /// Runs the commands of another chain file.
+    Call {
+        /// The path of the chain file, relative to the directory of the calling file.
+        path: PathBuf,
+        /// The variables that are set while the called chain runs.
+        #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+        params: BTreeMap<String, String>,
+        /// The called chain, once it has been loaded.
+        #[serde(skip)]
+        chain: Option<Box<CommandChain>>,
+    },
+    /// Waits until an area of the screen has not changed for the given duration.
+    WaitForStable {
+        /// The area of the screen that should become stable.
+        location: Anchored<Rect>,
+        /// How long the area must remain unchanged.
+        duration: Duration,
+        /// How much the area may change while still being considered unchanged.
+        #[serde(default)]
+        tolerance: Tolerance,
+        /// How long to wait for the area to become stable.
+        #[serde(flatten)]
+        wait: WaitOptions,
     },
     /// Sleeps for a specified duration.
     Sleep {
@@ -73,12 +949,32 @@ pub(crate) enum Command {
     Shell {
         /// The shell command to run.
         command: String,
+        /// How the output of the command is stored, if it is stored.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        capture: Option<Capture>,
     },
     /// Presses the given keys all at once in the given order.
     PressKeys {
         /// The keys to press.
         keys: Vec<u32>,
     },
+    /// Presses the given keys in the given order and keeps them pressed.
+    KeyDown {
+        /// The keys to press.
+        keys: Vec<u32>,
+    },
+    /// Releases the given keys in the given order.
+    KeyUp {
+        /// The keys to release.
+        keys: Vec<u32>,
+    },
+    /// Presses the given keys in the given order and releases them after the given duration.
+    HoldKeys {
+        /// The keys to hold.
+        keys: Vec<u32>,
+        /// How long the keys are held.
+        duration: Duration,
+    },
     /// Types the given text.
     Type {
         /// The text to type.
@@ -87,74 +983,694 @@ pub(crate) enum Command {
     /// Clicks on the given position.
     Click {
         /// The position to click onto.
-        position: Position,
+        position: Anchored<Position>,
+        /// Which mouse button to click how often.
+        #[serde(flatten)]
+        click: MouseClick,
+    },
+    /// Presses a mouse button at one position and releases it at another.
+    Drag {
+        /// The position where the button is pressed.
+        from: Anchored<Position>,
+        /// The position where the button is released.
+        to: Anchored<Position>,
+        /// The mouse button that is pressed.
+        #[serde(default)]
+        button: MouseButton,
+        /// How long it takes to move from one position to the other.
+        #[serde(default)]
+        duration: Duration,
+    },
+    /// Turns the mouse wheel at the given position.
+    Scroll {
+        /// The position to scroll at.
+        position: Anchored<Position>,
+        /// The number of horizontal wheel steps, positive values scroll to the right.
+        #[serde(default)]
+        dx: i32,
+        /// The number of vertical wheel steps, positive values scroll down.
+        #[serde(default)]
+        dy: i32,
     },
     /// Moves the mouse to the given position.
     MouseMove {
         /// The position to click onto.
-        position: Position,
+        position: Anchored<Position>,
     },
 }
 
 impl Command {
     /// Constructs a new wait for image command.
-    pub(crate) fn wait_for_image(click: bool) -> anyhow::Result<Option<Self>> {
+    fn wait_for_image(click: bool, recorder: &Recorder) -> anyhow::Result<Option<Self>> {
+        let Some(target) = ImageTarget::record(recorder)? else { return Ok(None) };
+        let click = if click { Some(query_click()?) } else { None };
+        let wait = WaitOptions::record(recorder)?;
+
+        Ok(Some(Command::WaitForImage {
+            target,
+            click,
+            wait,
+        }))
+    }
+
+    /// Constructs a new wait for image to disappear command.
+    fn wait_for_image_gone(recorder: &Recorder) -> anyhow::Result<Option<Self>> {
+        let Some(target) = ImageTarget::record(recorder)? else { return Ok(None) };
+        let wait = WaitOptions::record(recorder)?;
+
+        Ok(Some(Command::WaitForImageGone { target, wait }))
+    }
+
+    /// Constructs a new wait for any image command.
+    fn wait_for_any(recorder: &Recorder) -> anyhow::Result<Option<Self>> {
+        let mut branches = Vec::new();
+
+        loop {
+            if let Some(target) = ImageTarget::record(recorder)? {
+                println!("recording the commands to run when this image appears, select \"finish\" when done");
+                let commands = recorder.record_commands()?;
+                branches.push(ImageBranch { target, commands });
+            }
+
+            if !dialoguer::Confirm::new()
+                .with_prompt("Do you want to add another image?")
+                .interact()?
+            {
+                break;
+            }
+        }
+
+        if branches.is_empty() {
+            return Ok(None);
+        }
+
+        let wait = WaitOptions::record(recorder)?;
+
+        Ok(Some(Command::WaitForAny { branches, wait }))
+    }
+
+    /// Constructs a new wait for color command.
+    fn wait_for_color(recorder: &Recorder) -> anyhow::Result<Option<Self>> {
+        let Some(target) = ColorTarget::record(recorder)? else { return Ok(None) };
+        let wait = WaitOptions::record(recorder)?;
+
+        Ok(Some(Command::WaitForColor { target, wait }))
+    }
+
+    /// Constructs a new wait for text command.
+    fn wait_for_text(recorder: &Recorder) -> anyhow::Result<Option<Self>> {
         let Some(location) = query_rect(false)? else { return Ok(None) };
+        let pattern = TextPattern::record()?;
+        let wait = WaitOptions::record(recorder)?;
 
-        while !dialoguer::Confirm::new()
-            .with_prompt("Is the image presented as it should be?")
+        Ok(Some(Command::WaitForText {
+            location: recorder.anchor(location),
+            pattern,
+            wait,
+        }))
+    }
+
+    /// Constructs a new extract text command.
+    fn extract_text(recorder: &Recorder) -> anyhow::Result<Option<Self>> {
+        let Some(location) = query_rect(false)? else { return Ok(None) };
+        let variable = dialoguer::Input::new()
+            .with_prompt("enter the name of the variable to store the text in")
+            .interact_text()?;
+
+        Ok(Some(Command::ExtractText {
+            location: recorder.anchor(location),
+            variable,
+        }))
+    }
+
+    /// Constructs a new if command.
+    fn r#if(recorder: &Recorder) -> anyhow::Result<Option<Self>> {
+        let Some(condition) = Condition::record(recorder)? else { return Ok(None) };
+
+        println!(
+            "recording the commands to run if the condition holds, select \"finish\" when done"
+        );
+        let then = recorder.record_commands()?;
+
+        let r#else = if dialoguer::Confirm::new()
+            .with_prompt("Do you want to run commands if the condition does not hold?")
+            .default(false)
             .interact()?
-        {}
+        {
+            println!("recording the commands to run otherwise, select \"finish\" when done");
+            recorder.record_commands()?
+        } else {
+            Vec::new()
+        };
 
-        let Some(image) = take_screenshot(location)? else { return Ok(None) };
+        Ok(Some(Command::If {
+            condition,
+            then,
+            r#else,
+        }))
+    }
 
-        Ok(Some(Command::WaitForImage {
-            location,
-            image,
-            click,
+    /// Constructs a new repeat command.
+    fn repeat(recorder: &Recorder) -> anyhow::Result<Option<Self>> {
+        let count = dialoguer::Input::new()
+            .with_prompt("enter how often the commands should be run")
+            .interact_text()?;
+
+        println!("recording the commands to repeat, select \"finish\" when done");
+        let body = recorder.record_commands()?;
+
+        Ok(Some(Command::Repeat { count, body }))
+    }
+
+    /// Constructs a new retry command.
+    fn retry(recorder: &Recorder) -> anyhow::Result<Option<Self>> {
+        let attempts = loop {
+            let attempts = dialoguer::Input::new()
+                .with_prompt("enter how often the commands should be attempted at most")
+                .default(3)
+                .interact_text()?;
+            if attempts > 0 {
+                break attempts;
+            }
+            println!("the commands must be attempted at least once");
+        };
+        let delay = query_optional_duration(
+            "enter the delay between attempts in seconds or leave empty to retry immediately",
+        )?
+        .unwrap_or_default();
+
+        println!("recording the commands to attempt, select \"finish\" when done");
+        let body = recorder.record_commands()?;
+
+        let on_failure = if dialoguer::Confirm::new()
+            .with_prompt("Do you want to run recovery commands after a failed attempt?")
+            .default(false)
+            .interact()?
+        {
+            println!("recording the recovery commands, select \"finish\" when done");
+            recorder.record_commands()?
+        } else {
+            Vec::new()
+        };
+
+        Ok(Some(Command::Retry {
+            attempts,
+            delay,
+            body,
+            on_failure,
+        }))
+    }
+
+    /// Constructs a new while command.
+    fn r#while(recorder: &Recorder) -> anyhow::Result<Option<Self>> {
+        let Some(condition) = Condition::record(recorder)? else { return Ok(None) };
+        let max_iterations = dialoguer::Input::new()
+            .with_prompt("enter the number of iterations after which the run fails")
+            .default(DEFAULT_MAX_ITERATIONS)
+            .interact_text()?;
+
+        println!("recording the commands to repeat, select \"finish\" when done");
+        let body = recorder.record_commands()?;
+
+        Ok(Some(Command::While {
+            condition,
+            body,
+            max_iterations,
+        }))
+    }
+
+    /// Constructs a new call command.
+    fn call() -> anyhow::Result<Option<Self>> {
+        let path = dialoguer::Input::<String>::new()
+            .with_prompt("enter the path of the chain file relative to the recorded file")
+            .interact_text()?;
+
+        let mut params = BTreeMap::new();
+        loop {
+            let param = dialoguer::Input::<String>::new()
+                .with_prompt("enter a parameter as `name=value` or leave empty to finish")
+                .allow_empty(true)
+                .interact_text()?;
+            if param.is_empty() {
+                break;
+            }
+
+            let Some((name, value)) = param.split_once('=') else { continue };
+            params.insert(name.trim().to_string(), value.to_string());
+        }
+
+        Ok(Some(Command::Call {
+            path: path.into(),
+            params,
+            chain: None,
+        }))
+    }
+
+    /// Constructs a new drag command.
+    fn drag(recorder: &Recorder) -> anyhow::Result<Option<Self>> {
+        println!("select the position to start dragging at");
+        let Some(from) = query_rect(true)? else { return Ok(None) };
+        println!("select the position to stop dragging at");
+        let Some(to) = query_rect(true)? else { return Ok(None) };
+
+        let button = MouseButton::ALL[dialoguer::Select::new()
+            .with_prompt("select the mouse button to drag with")
+            .items(MouseButton::ALL)
+            .default(0)
+            .interact()?];
+        let duration = query_optional_duration(
+            "enter how many seconds the drag should take or leave empty to drag immediately",
+        )?
+        .unwrap_or_default();
+
+        Ok(Some(Command::Drag {
+            from: recorder.anchor_position(from.origin()),
+            to: recorder.anchor_position(to.origin()),
+            button,
+            duration,
+        }))
+    }
+
+    /// Constructs a new scroll command.
+    fn scroll(recorder: &Recorder) -> anyhow::Result<Option<Self>> {
+        let Some(rect) = query_rect(true)? else { return Ok(None) };
+        let dy = dialoguer::Input::new()
+            .with_prompt("enter the number of wheel steps to scroll down (negative to scroll up)")
+            .default(0)
+            .interact_text()?;
+        let dx = dialoguer::Input::new()
+            .with_prompt(
+                "enter the number of wheel steps to scroll right (negative to scroll left)",
+            )
+            .default(0)
+            .interact_text()?;
+
+        Ok(Some(Command::Scroll {
+            position: recorder.anchor_position(rect.origin()),
+            dx,
+            dy,
+        }))
+    }
+
+    /// Constructs a new wait for stable area command.
+    fn wait_for_stable(recorder: &Recorder) -> anyhow::Result<Option<Self>> {
+        let Some(location) = query_rect(false)? else { return Ok(None) };
+
+        let duration = loop {
+            if let Some(duration) =
+                query_optional_duration("enter how many seconds the area must remain unchanged")?
+            {
+                break duration;
+            }
+        };
+        let tolerance = query_tolerance()?;
+        let wait = WaitOptions::record(recorder)?;
+
+        Ok(Some(Command::WaitForStable {
+            location: recorder.anchor(location),
+            duration,
+            tolerance,
+            wait,
         }))
     }
 
+    /// Loads the chains called by the command relative to the directory `dir`.
+    ///
+    /// The `stack` contains the chain files that are currently being loaded to detect cycles.
+    fn load_calls(&mut self, dir: &Path, stack: &mut Vec<PathBuf>) -> anyhow::Result<()> {
+        match self {
+            Self::WaitForImage { wait, .. }
+            | Self::WaitForImageGone { wait, .. }
+            | Self::WaitForColor { wait, .. }
+            | Self::WaitForText { wait, .. }
+            | Self::WaitForStable { wait, .. } => wait.load_calls(dir, stack)?,
+            Self::WaitForAny { branches, wait } => {
+                for branch in branches {
+                    load_calls(&mut branch.commands, dir, stack)?;
+                }
+                wait.load_calls(dir, stack)?;
+            }
+            Self::If { then, r#else, .. } => {
+                load_calls(then, dir, stack)?;
+                load_calls(r#else, dir, stack)?;
+            }
+            Self::Repeat { body, .. } | Self::While { body, .. } => load_calls(body, dir, stack)?,
+            Self::Retry {
+                body, on_failure, ..
+            } => {
+                load_calls(body, dir, stack)?;
+                load_calls(on_failure, dir, stack)?;
+            }
+            Self::Call { path, chain, .. } => {
+                *chain = Some(Box::new(CommandChain::load_nested(&dir.join(path), stack)?));
+            }
+            Self::AssertColor { .. }
+            | Self::ExtractText { .. }
+            | Self::Sleep { .. }
+            | Self::Shell { .. }
+            | Self::PressKeys { .. }
+            | Self::KeyDown { .. }
+            | Self::KeyUp { .. }
+            | Self::HoldKeys { .. }
+            | Self::Type { .. }
+            | Self::Click { .. }
+            | Self::Drag { .. }
+            | Self::Scroll { .. }
+            | Self::MouseMove { .. } => {}
+        }
+
+        Ok(())
+    }
+
+    /// Adapts the positions and images of the command to the current screen.
+    fn adapt(&mut self, adapter: &Adapter) -> anyhow::Result<()> {
+        match self {
+            Self::WaitForImage { target, wait, .. } | Self::WaitForImageGone { target, wait } => {
+                target.adapt(adapter)?;
+                wait.adapt(adapter)?;
+            }
+            Self::WaitForAny { branches, wait } => {
+                for branch in branches {
+                    branch.target.adapt(adapter)?;
+                    adapt_commands(&mut branch.commands, adapter)?;
+                }
+                wait.adapt(adapter)?;
+            }
+            Self::WaitForColor { target, wait } => {
+                target.adapt(adapter)?;
+                wait.adapt(adapter)?;
+            }
+            Self::AssertColor { target } => target.adapt(adapter)?,
+            Self::WaitForText { location, wait, .. }
+            | Self::WaitForStable { location, wait, .. } => {
+                adapter.rect(location)?;
+                wait.adapt(adapter)?;
+            }
+            Self::ExtractText { location, .. } => adapter.rect(location)?,
+            Self::If {
+                condition,
+                then,
+                r#else,
+            } => {
+                condition.adapt(adapter)?;
+                adapt_commands(then, adapter)?;
+                adapt_commands(r#else, adapter)?;
+            }
+            Self::Repeat { body, .. } => adapt_commands(body, adapter)?,
+            Self::Retry {
+                body, on_failure, ..
+            } => {
+                adapt_commands(body, adapter)?;
+                adapt_commands(on_failure, adapter)?;
+            }
+            Self::Call { chain, .. } => {
+                if let Some(chain) = chain {
+                    chain.adapt(&adapter.for_chain(chain.geometry.as_ref()))?;
+                }
+            }
+            Self::While {
+                condition, body, ..
+            } => {
+                condition.adapt(adapter)?;
+                adapt_commands(body, adapter)?;
+            }
+            Self::Click { position, .. }
+            | Self::Scroll { position, .. }
+            | Self::MouseMove { position } => {
+                adapter.position(position)?;
+            }
+            Self::Drag { from, to, .. } => {
+                adapter.position(from)?;
+                adapter.position(to)?;
+            }
+            Self::Sleep { .. }
+            | Self::Shell { .. }
+            | Self::PressKeys { .. }
+            | Self::KeyDown { .. }
+            | Self::KeyUp { .. }
+            | Self::HoldKeys { .. }
+            | Self::Type { .. } => {}
+        }
+
+        Ok(())
+    }
+
     /// Executes the command.
-    pub(crate) fn execute(&self) -> anyhow::Result<()> {
+    fn execute<'a>(&'a self, state: &mut ExecutionState<'a>) -> anyhow::Result<()> {
         match self {
             Self::WaitForImage {
-                location,
-                image,
+                target,
                 click,
+                wait,
+            } => {
+                if let Some(found) = wait.wait(state, "image", &[target.area()], |frame| {
+                    Ok(target.find(frame))
+                })? {
+                    if let Some(click) = click {
+                        ydotool::click(found.center(), *click)?;
+                    }
+                }
+            }
+            Self::WaitForImageGone { target, wait } => {
+                wait.wait(state, "image to disappear", &[target.area()], |frame| {
+                    Ok(target.find(frame).is_none().then_some(()))
+                })?;
+            }
+            Self::WaitForAny { branches, wait } => {
+                let areas = branches
+                    .iter()
+                    .map(|branch| branch.target.area())
+                    .collect::<Vec<_>>();
+                if let Some(branch) = wait.wait(state, "any image", &areas, |frame| {
+                    Ok(branches
+                        .iter()
+                        .find(|branch| branch.target.find(frame).is_some()))
+                })? {
+                    execute_commands(&branch.commands, state)?;
+                }
+            }
+            Self::WaitForColor { target, wait } => {
+                wait.wait(state, "color", &[target.area()], |frame| {
+                    Ok(target.matches(frame).then_some(()))
+                })?;
+            }
+            Self::AssertColor { target } => {
+                let Some(frame) = Frame::capture([target.area()])? else {
+                    anyhow::bail!("failed to capture the screen at {}", target.position)
+                };
+                if !target.matches(&frame) {
+                    let found = target
+                        .sample(&frame)
+                        .map_or_else(|| "nothing".to_string(), |color| color.to_string());
+                    anyhow::bail!(
+                        "expected color {} at {}, but found {found}",
+                        target.color,
+                        target.position,
+                    );
+                }
+            }
+            Self::WaitForText {
+                location,
+                pattern,
+                wait,
+            } => {
+                let regex = pattern.to_regex()?;
+                wait.wait(state, "text", &[location.value], |frame| {
+                    let Some(image) = frame.crop(location.value) else { return Ok(None) };
+                    Ok(regex.is_match(&recognize_text(&image)?).then_some(()))
+                })?;
+            }
+            Self::ExtractText { location, variable } => {
+                let Some(frame) = Frame::capture([location.value])? else {
+                    anyhow::bail!("failed to capture the screen at {location}")
+                };
+                let Some(image) = frame.crop(location.value) else {
+                    anyhow::bail!("failed to capture the screen at {location}")
+                };
+                let text = recognize_text(&image)?.trim().to_string();
+                println!("extracted text {text:?} into `{variable}`");
+                state.variables.insert(variable.clone(), text);
+            }
+            Self::If {
+                condition,
+                then,
+                r#else,
+            } => {
+                if condition.evaluate(state)? {
+                    execute_commands(then, state)?;
+                } else {
+                    execute_commands(r#else, state)?;
+                }
+            }
+            Self::Repeat { count, body } => {
+                for iteration in 0..*count {
+                    execute_commands(body, state)
+                        .with_context(|| format!("failed in iteration {}", iteration + 1))?;
+                }
+            }
+            Self::While {
+                condition,
+                body,
+                max_iterations,
+            } => {
+                let mut iteration = 0;
+                while condition.evaluate(state)? {
+                    if iteration == *max_iterations {
+                        anyhow::bail!("condition still holds after {max_iterations} iterations");
+                    }
+                    iteration += 1;
+                    execute_commands(body, state)
+                        .with_context(|| format!("failed in iteration {iteration}"))?;
+                }
+            }
+            Self::Retry {
+                attempts,
+                delay,
+                body,
+                on_failure,
             } => {
+                let attempts = (*attempts).max(1);
+                let mut attempt = 1;
                 loop {
-                    let Some(curr_image) = take_screenshot(*location)? else { continue };
-                    if &curr_image == image {
-                        break;
+                    let Err(err) = execute_commands(body, state) else { break };
+                    println!("attempt {attempt}/{attempts} failed: {err:#}");
+                    if attempt >= attempts {
+                        return Err(err.context(format!("failed after {attempt} attempts")));
                     }
+
+                    state.release_keys()?;
+                    execute_commands(on_failure, state)
+                        .context("failed to recover from a failed attempt")?;
+                    std::thread::sleep(*delay);
+                    attempt += 1;
                 }
-                if *click {
-                    ydotool::click(location.center())?;
+            }
+            Self::Call {
+                path,
+                params,
+                chain,
+            } => {
+                let Some(chain) = chain else {
+                    anyhow::bail!("the chain `{}` was not loaded", path.display())
+                };
+
+                let params = params
+                    .iter()
+                    .map(|(name, value)| Ok((name, state.interpolate(value)?)))
+                    .collect::<anyhow::Result<Vec<_>>>()?;
+                let previous_variables = params
+                    .into_iter()
+                    .map(|(name, value)| (name, state.variables.insert(name.clone(), value)))
+                    .collect::<Vec<_>>();
+                let previous_timeout = state.default_timeout;
+                state.default_timeout = chain.default_timeout.or(previous_timeout);
+                let previous_watchers = state.watchers.len();
+                state.watchers.extend(&chain.watchers);
+
+                let result = chain
+                    .execute_sections(state)
+                    .with_context(|| format!("failed in called chain `{}`", path.display()));
+
+                state.default_timeout = previous_timeout;
+                state.watchers.truncate(previous_watchers);
+                for (name, value) in previous_variables {
+                    match value {
+                        Some(value) => state.variables.insert(name.clone(), value),
+                        None => state.variables.remove(name),
+                    };
                 }
+
+                result?;
+            }
+            Self::WaitForStable {
+                location,
+                duration,
+                tolerance,
+                wait,
+            } => {
+                let mut last_change: Option<(RgbImage, Instant)> = None;
+                wait.wait(state, "stable screen", &[location.value], |frame| {
+                    let Some(image) = frame.crop(location.value) else { return Ok(None) };
+                    match &last_change {
+                        Some((last, since)) if tolerance.matches(last, &image) => {
+                            Ok((since.elapsed() >= *duration).then_some(()))
+                        }
+                        _ => {
+                            last_change = Some((image, Instant::now()));
+                            Ok(None)
+                        }
+                    }
+                })?;
             }
             Self::Sleep { duration } => {
                 std::thread::sleep(*duration);
             }
-            Self::Shell { command } => {
-                let status = std::process::Command::new("bash")
-                    .args(["-c", command])
-                    .status()?;
-                if !status.success() {
-                    anyhow::bail!("shell command `{command}` exited with status {status}");
+            Self::Shell { command, capture } => {
+                let (command, mut process) = state.shell(command)?;
+
+                if let Some(capture) = capture {
+                    let output = process.stderr(std::process::Stdio::inherit()).output()?;
+                    if !output.status.success() {
+                        anyhow::bail!(
+                            "shell command `{command}` exited with status {}",
+                            output.status
+                        );
+                    }
+
+                    let value = capture
+                        .extract(&String::from_utf8_lossy(&output.stdout))
+                        .with_context(|| format!("failed to capture the output of `{command}`"))?;
+                    println!(
+                        "stored the output of the shell command in `{}`",
+                        capture.variable
+                    );
+                    state.variables.insert(capture.variable.clone(), value);
+                } else {
+                    let status = process.status()?;
+                    if !status.success() {
+                        anyhow::bail!("shell command `{command}` exited with status {status}");
+                    }
                 }
             }
             Self::PressKeys { keys } => {
                 ydotool::press_keys(keys)?;
             }
+            Self::KeyDown { keys } => {
+                ydotool::key_down(keys)?;
+                state.held_keys.retain(|key| !keys.contains(key));
+                state.held_keys.extend(keys);
+            }
+            Self::KeyUp { keys } => {
+                ydotool::key_up(keys)?;
+                state.held_keys.retain(|key| !keys.contains(key));
+            }
+            Self::HoldKeys { keys, duration } => {
+                ydotool::key_down(keys)?;
+                state.held_keys.retain(|key| !keys.contains(key));
+                state.held_keys.extend(keys);
+                std::thread::sleep(*duration);
+                ydotool::key_up(&keys.iter().rev().copied().collect::<Vec<_>>())?;
+                state.held_keys.retain(|key| !keys.contains(key));
+            }
             Self::Type { text } => {
-                ydotool::r#type(text)?;
+                ydotool::r#type(&state.interpolate(text)?)?;
+            }
+            Self::Click { position, click } => {
+                ydotool::click(position.value, *click)?;
+            }
+            Self::Drag {
+                from,
+                to,
+                button,
+                duration,
+            } => {
+                ydotool::drag(from.value, to.value, *button, *duration)?;
             }
-            Self::Click { position } => {
-                ydotool::click(*position)?;
+            Self::Scroll { position, dx, dy } => {
+                ydotool::scroll(position.value, *dx, *dy)?;
             }
             Self::MouseMove { position } => {
-                ydotool::move_mouse(*position)?;
+                ydotool::move_mouse(position.value)?;
             }
         }
 
@@ -162,31 +1678,200 @@ impl Command {
     }
 }
 
+/// Loads the chains called by the given commands relative to the directory `dir`.
+fn load_calls(
+    commands: &mut [Command],
+    dir: &Path,
+    stack: &mut Vec<PathBuf>,
+) -> anyhow::Result<()> {
+    for command in commands {
+        command.load_calls(dir, stack)?;
+    }
+
+    Ok(())
+}
+
+/// Adapts the positions and images of the given commands to the current screen.
+fn adapt_commands(commands: &mut [Command], adapter: &Adapter) -> anyhow::Result<()> {
+    for command in commands {
+        command.adapt(adapter)?;
+    }
+
+    Ok(())
+}
+
+/// Executes the given commands in order.
+fn execute_commands<'a>(
+    commands: &'a [Command],
+    state: &mut ExecutionState<'a>,
+) -> anyhow::Result<()> {
+    for (idx, command) in commands.iter().enumerate() {
+        state.check_watchers()?;
+        command
+            .execute(state)
+            .with_context(|| format!("failed to execute step {}", idx + 1))?;
+    }
+
+    Ok(())
+}
+
+/// Queries the user for the mouse button to click and how often to click it.
+fn query_click() -> anyhow::Result<MouseClick> {
+    let button = MouseButton::ALL[dialoguer::Select::new()
+        .with_prompt("select the mouse button to click")
+        .items(MouseButton::ALL)
+        .default(0)
+        .interact()?];
+    let count = loop {
+        let count = dialoguer::Input::new()
+            .with_prompt("enter how often to click")
+            .default(1)
+            .interact_text()?;
+        if count > 0 {
+            break count;
+        }
+        println!("the button must be clicked at least once");
+    };
+
+    Ok(MouseClick { button, count })
+}
+
+/// Queries the user for the tolerance to use when comparing images.
+fn query_tolerance() -> anyhow::Result<Tolerance> {
+    let channel = dialoguer::Input::<u8>::new()
+        .with_prompt("enter the allowed difference per color channel")
+        .default(0)
+        .interact_text()?;
+
+    let mismatch = loop {
+        let percent = dialoguer::Input::<f64>::new()
+            .with_prompt("enter the percentage of pixels that may differ")
+            .default(0.0)
+            .interact_text()?;
+        if (0.0..=100.0).contains(&percent) {
+            break percent / 100.0;
+        }
+    };
+
+    Ok(Tolerance { channel, mismatch })
+}
+
+/// Queries the user for an optional duration in seconds.
+///
+/// Returns `None` if the user leaves the input empty.
+fn query_optional_duration(prompt: &str) -> anyhow::Result<Option<Duration>> {
+    loop {
+        let input = dialoguer::Input::<String>::new()
+            .with_prompt(prompt)
+            .allow_empty(true)
+            .interact_text()?;
+        if input.trim().is_empty() {
+            return Ok(None);
+        }
+
+        let Ok(secs) = input.trim().parse::<f64>() else { continue };
+        if secs.is_finite() && secs.is_sign_positive() {
+            return Ok(Some(Duration::from_secs_f64(secs)));
+        }
+    }
+}
+
 /// The dialogue options presented to the user.
 const OPTIONS: &[&str] = &[
     "wait for image and click",
     "wait for image",
+    "wait for image to disappear",
+    "wait for any of several images",
+    "wait for stable area",
+    "wait for color",
+    "assert color",
+    "wait for text",
+    "extract text",
+    "if",
+    "repeat",
+    "repeat while",
+    "retry",
+    "call chain",
     "click",
+    "drag",
+    "scroll",
     "move mouse",
     "press keys",
+    "key down",
+    "key up",
+    "hold keys",
     "type text",
     "shell command",
     "sleep",
-    "exit run",
+    "finish",
 ];
 
-/// Contains commands that should be executed in a chain.
-#[derive(serde::Serialize, serde::Deserialize)]
-pub(crate) struct CommandChain {
-    /// The commands in the chain.
-    commands: Vec<Command>,
+/// Holds the information needed while recording commands.
+struct Recorder {
+    /// The available key codes.
+    key_codes: crate::key_codes::KeyCodes,
+    /// The timeout used by waiting commands that do not specify their own.
+    default_timeout: Option<Duration>,
+    /// The outputs positions are recorded relative to, if positions should be bound to outputs.
+    anchor_outputs: Option<Vec<Output>>,
 }
 
-impl CommandChain {
-    /// Records a new chain of commands.
-    pub(crate) fn record() -> anyhow::Result<Self> {
-        let key_codes = crate::key_codes::KeyCodes::new()?;
+impl Recorder {
+    /// Returns the output containing the given position if positions should be bound to outputs.
+    fn output_at(&self, Position { x, y }: Position) -> Option<&Output> {
+        self.anchor_outputs.as_ref()?.iter().find(|output| {
+            (output.area.x..output.area.x + output.area.width).contains(&x)
+                && (output.area.y..output.area.y + output.area.height).contains(&y)
+        })
+    }
+
+    /// Binds the position to the output it is on, if positions should be bound to outputs.
+    fn anchor_position(&self, position: Position) -> Anchored<Position> {
+        match self.output_at(position) {
+            Some(output) => Anchored {
+                value: Position {
+                    x: position.x - output.area.x,
+                    y: position.y - output.area.y,
+                },
+                output: Some(output.name.clone()),
+            },
+            None => Anchored::global(position),
+        }
+    }
+
+    /// Binds the rectangle to the output its origin is on, if positions should be bound to
+    /// outputs.
+    fn anchor(&self, rect: Rect) -> Anchored<Rect> {
+        let Anchored { value, output } = self.anchor_position(rect.origin());
+
+        Anchored {
+            value: Rect {
+                x: value.x,
+                y: value.y,
+                ..rect
+            },
+            output,
+        }
+    }
+
+    /// Queries the user for keys until they finish selecting keys.
+    fn query_keys(&self) -> anyhow::Result<Vec<u32>> {
+        let mut keys = Vec::new();
+        while let Some(index) = dialoguer::FuzzySelect::new()
+            .with_prompt("select a key code or press a ESC to finish selecting key codes")
+            .items(self.key_codes.codes())
+            .interact_opt()?
+        {
+            if let Some(key) = self.key_codes.get_num(index) {
+                keys.push(key);
+            }
+        }
+
+        Ok(keys)
+    }
 
+    /// Records commands until the user chooses to finish.
+    fn record_commands(&self) -> anyhow::Result<Vec<Command>> {
         let mut commands = Vec::new();
 
         loop {
@@ -197,29 +1882,53 @@ impl CommandChain {
                 .interact()?]
             {
                 option @ ("wait for image and click" | "wait for image") => {
-                    Command::wait_for_image(option == "wait for image and click")?
+                    Command::wait_for_image(option == "wait for image and click", self)?
                 }
-                "click" => query_rect(true)?.map(|rect| Command::Click {
-                    position: rect.origin(),
-                }),
+                "wait for image to disappear" => Command::wait_for_image_gone(self)?,
+                "wait for any of several images" => Command::wait_for_any(self)?,
+                "wait for stable area" => Command::wait_for_stable(self)?,
+                "wait for color" => Command::wait_for_color(self)?,
+                "assert color" => {
+                    ColorTarget::record(self)?.map(|target| Command::AssertColor { target })
+                }
+                "wait for text" => Command::wait_for_text(self)?,
+                "extract text" => Command::extract_text(self)?,
+                "if" => Command::r#if(self)?,
+                "repeat" => Command::repeat(self)?,
+                "repeat while" => Command::r#while(self)?,
+                "retry" => Command::retry(self)?,
+                "call chain" => Command::call()?,
+                "click" => match query_rect(true)? {
+                    Some(rect) => Some(Command::Click {
+                        position: self.anchor_position(rect.origin()),
+                        click: query_click()?,
+                    }),
+                    None => None,
+                },
+                "drag" => Command::drag(self)?,
+                "scroll" => Command::scroll(self)?,
                 "move mouse" => query_rect(true)?.map(|rect| Command::MouseMove {
-                    position: rect.origin(),
+                    position: self.anchor_position(rect.origin()),
+                }),
+                "press keys" => Some(Command::PressKeys {
+                    keys: self.query_keys()?,
+                }),
+                "key down" => Some(Command::KeyDown {
+                    keys: self.query_keys()?,
+                }),
+                "key up" => Some(Command::KeyUp {
+                    keys: self.query_keys()?,
                 }),
-                "press keys" => {
-                    let mut keys = Vec::new();
-                    while let Some(index) = dialoguer::FuzzySelect::new()
-                        .with_prompt(
-                            "select a key code or press a ESC to finish selecting key codes",
-                        )
-                        .items(key_codes.codes())
-                        .interact_opt()?
-                    {
-                        if let Some(key) = key_codes.get_num(index) {
-                            keys.push(key);
+                "hold keys" => {
+                    let keys = self.query_keys()?;
+                    let duration = loop {
+                        if let Some(duration) = query_optional_duration(
+                            "enter how many seconds the keys should be held",
+                        )? {
+                            break duration;
                         }
-                    }
-
-                    Some(Command::PressKeys { keys })
+                    };
+                    Some(Command::HoldKeys { keys, duration })
                 }
                 "type text" => {
                     let text = dialoguer::Input::new()
@@ -231,7 +1940,8 @@ impl CommandChain {
                     let command = dialoguer::Input::new()
                         .with_prompt("enter the shell command to execute")
                         .interact_text()?;
-                    Some(Command::Shell { command })
+                    let capture = Capture::record()?;
+                    Some(Command::Shell { command, capture })
                 }
                 "sleep" => {
                     let secs = loop {
@@ -247,7 +1957,7 @@ impl CommandChain {
                         duration: std::time::Duration::from_secs_f64(secs),
                     })
                 }
-                "exit run" => break,
+                "finish" => break,
                 _ => continue,
             };
 
@@ -256,87 +1966,950 @@ impl CommandChain {
             }
         }
 
-        Ok(Self { commands })
+        Ok(commands)
+    }
+}
+
+/// The state that is shared between commands while a chain is executed.
+struct ExecutionState<'a> {
+    /// The timeout used by waiting commands that do not specify their own.
+    default_timeout: Option<Duration>,
+    /// How often the screen is checked while waiting.
+    polling: Polling,
+    /// The values of the variables set so far.
+    variables: BTreeMap<String, String>,
+    /// The watchers that are checked between steps and while waiting.
+    watchers: Vec<&'a ImageBranch>,
+    /// Whether the commands of a watcher are currently running.
+    handling_watcher: bool,
+    /// The keys that were pressed and not yet released, in the order they were pressed.
+    held_keys: Vec<u32>,
+}
+
+impl<'a> ExecutionState<'a> {
+    /// Returns the areas of the screen the active watchers look at.
+    fn watcher_areas(&self) -> Vec<Rect> {
+        if self.handling_watcher {
+            return Vec::new();
+        }
+
+        self.watchers
+            .iter()
+            .map(|watcher| watcher.target.area())
+            .collect()
+    }
+
+    /// Runs the commands of the first watcher whose image is present in the frame.
+    ///
+    /// Returns whether a watcher was triggered.
+    fn handle_watchers(&mut self, frame: &Frame) -> anyhow::Result<bool> {
+        if self.handling_watcher {
+            return Ok(false);
+        }
+        let Some(watcher) = self
+            .watchers
+            .iter()
+            .copied()
+            .find(|watcher| watcher.target.find(frame).is_some())
+        else {
+            return Ok(false);
+        };
+
+        println!(
+            "{} appeared, running its commands",
+            watcher.target.describe()
+        );
+        self.handling_watcher = true;
+        let result = execute_commands(&watcher.commands, self);
+        self.handling_watcher = false;
+        result.with_context(|| format!("failed to handle {}", watcher.target.describe()))?;
+
+        Ok(true)
+    }
+
+    /// Releases the keys that are still held in reverse order.
+    ///
+    /// This happens at the end of a run and before recovering from a failure, so that the
+    /// recovery does not run with keys pressed.
+    fn release_keys(&mut self) -> anyhow::Result<()> {
+        if self.held_keys.is_empty() {
+            return Ok(());
+        }
+
+        println!("releasing {} held keys", self.held_keys.len());
+        let keys = std::mem::take(&mut self.held_keys);
+        ydotool::key_up(&keys.into_iter().rev().collect::<Vec<_>>())
+            .context("failed to release the held keys")
+    }
+
+    /// Captures the screen and runs the commands of a watcher if its image is present.
+    fn check_watchers(&mut self) -> anyhow::Result<()> {
+        let areas = self.watcher_areas();
+        if areas.is_empty() {
+            return Ok(());
+        }
+
+        if let Some(frame) = Frame::capture(areas)? {
+            self.handle_watchers(&frame)?;
+        }
+
+        Ok(())
+    }
+
+    /// Replaces every `{{name}}` in the text with the value of the variable `name`.
+    fn interpolate(&self, text: &str) -> anyhow::Result<String> {
+        interpolate(text, &self.variables)
+    }
+
+    /// Prepares running a shell command with the variables set in its environment.
+    ///
+    /// Placeholders in the command are replaced with the value of their variable as is, so
+    /// commands should refer to values that may contain special characters as `"$name"`.
+    fn shell(&self, command: &str) -> anyhow::Result<(String, std::process::Command)> {
+        let command = self.interpolate(command)?;
+        let mut process = std::process::Command::new("bash");
+        process.args(["-c", &command]).envs(&self.variables);
+        Ok((command, process))
+    }
+}
+
+/// Replaces every `{{name}}` in the text with the value of the variable `name`, and every `{{{{`
+/// with a literal `{{`.
+fn interpolate(text: &str, variables: &BTreeMap<String, String>) -> anyhow::Result<String> {
+    let placeholder = regex::Regex::new(r"\{\{\{\{|\{\{\s*([^{}\s]+)\s*\}\}")?;
+
+    let mut result = String::new();
+    let mut last_end = 0;
+    for captures in placeholder.captures_iter(text) {
+        let Some(whole) = captures.get(0) else { continue };
+        result.push_str(&text[last_end..whole.start()]);
+        match captures.get(1) {
+            Some(name) => {
+                let Some(value) = variables.get(name.as_str()) else {
+                    anyhow::bail!("variable `{}` is not set", name.as_str())
+                };
+                result.push_str(value);
+            }
+            None => result.push_str("{{"),
+        }
+        last_end = whole.end();
+    }
+    result.push_str(&text[last_end..]);
+
+    Ok(result)
+}
+
+/// Contains commands that should be executed in a chain.
+#[derive(serde::Serialize, serde::Deserialize)]
+pub(crate) struct CommandChain {
+    /// The commands that prepare the run before the commands of the chain.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    setup: Vec<Command>,
+    /// The commands in the chain.
+    commands: Vec<Command>,
+    /// The commands that run at the end of the chain, even if an earlier command failed.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    teardown: Vec<Command>,
+    /// The timeout used by waiting commands that do not specify their own.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    default_timeout: Option<Duration>,
+    /// How often the screen is checked while waiting.
+    #[serde(default)]
+    polling: Polling,
+    /// The geometry of the screen the chain was recorded on.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    geometry: Option<Geometry>,
+    /// The images that interrupt the chain to run their commands whenever they appear.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    watchers: Vec<ImageBranch>,
+}
+
+impl CommandChain {
+    /// Loads the chain stored in the given file together with all chains it calls.
+    pub(crate) fn load(path: &Path) -> anyhow::Result<Self> {
+        CommandChain::load_nested(path, &mut Vec::new())
+    }
+
+    /// Loads the chain stored in the given file while the chains in `stack` are being loaded.
+    fn load_nested(path: &Path, stack: &mut Vec<PathBuf>) -> anyhow::Result<Self> {
+        let canonical = path
+            .canonicalize()
+            .with_context(|| format!("failed to find the chain `{}`", path.display()))?;
+        if stack.contains(&canonical) {
+            anyhow::bail!("the chain `{}` calls itself", path.display());
+        }
+
+        let mut chain: CommandChain = serde_json::from_reader(std::fs::File::open(&canonical)?)
+            .with_context(|| format!("failed to load the chain `{}`", path.display()))?;
+
+        let dir = canonical.parent().unwrap_or(Path::new("/")).to_path_buf();
+        stack.push(canonical);
+        load_calls(&mut chain.setup, &dir, stack)?;
+        load_calls(&mut chain.commands, &dir, stack)?;
+        load_calls(&mut chain.teardown, &dir, stack)?;
+        for watcher in &mut chain.watchers {
+            load_calls(&mut watcher.commands, &dir, stack)?;
+        }
+        stack.pop();
+
+        Ok(chain)
+    }
+
+    /// Records a new chain of commands.
+    pub(crate) fn record() -> anyhow::Result<Self> {
+        let default_timeout = query_optional_duration(
+            "enter the default timeout for waiting in seconds or leave empty to wait forever",
+        )?;
+
+        let outputs = match query_outputs() {
+            Ok(outputs) => outputs,
+            Err(err) => {
+                println!("could not determine the outputs, the chain will not adapt to other screens: {err}");
+                Vec::new()
+            }
+        };
+        let geometry = Geometry::of_outputs(&outputs);
+
+        let anchor_outputs = if outputs.len() > 1
+            && dialoguer::Confirm::new()
+                .with_prompt("Should positions be recorded relative to the output they are on?")
+                .default(false)
+                .interact()?
+        {
+            Some(outputs)
+        } else {
+            None
+        };
+
+        let recorder = Recorder {
+            key_codes: crate::key_codes::KeyCodes::new()?,
+            default_timeout,
+            anchor_outputs,
+        };
+
+        let mut watchers = Vec::new();
+        while dialoguer::Confirm::new()
+            .with_prompt("Do you want to add a watcher for an image that can appear at any time?")
+            .default(false)
+            .interact()?
+        {
+            if let Some(target) = ImageTarget::record(&recorder)? {
+                println!("recording the commands to run whenever this image appears, select \"finish\" when done");
+                let commands = recorder.record_commands()?;
+                watchers.push(ImageBranch { target, commands });
+            }
+        }
+
+        let setup = if dialoguer::Confirm::new()
+            .with_prompt("Do you want to record setup commands that run before the chain?")
+            .default(false)
+            .interact()?
+        {
+            println!("recording the setup commands, select \"finish\" when done");
+            recorder.record_commands()?
+        } else {
+            Vec::new()
+        };
+
+        println!("recording the commands of the chain, select \"finish\" when done");
+        let commands = recorder.record_commands()?;
+
+        let teardown = if dialoguer::Confirm::new()
+            .with_prompt("Do you want to record teardown commands that always run at the end?")
+            .default(false)
+            .interact()?
+        {
+            println!("recording the teardown commands, select \"finish\" when done");
+            recorder.record_commands()?
+        } else {
+            Vec::new()
+        };
+
+        Ok(Self {
+            setup,
+            commands,
+            teardown,
+            default_timeout,
+            polling: Polling::default(),
+            geometry,
+            watchers,
+        })
+    }
+
+    /// Adapts the commands and watchers of the chain using the given adapter.
+    fn adapt(&mut self, adapter: &Adapter) -> anyhow::Result<()> {
+        adapt_commands(&mut self.setup, adapter)?;
+        adapt_commands(&mut self.commands, adapter)?;
+        adapt_commands(&mut self.teardown, adapter)?;
+        for watcher in &mut self.watchers {
+            watcher.target.adapt(adapter)?;
+            adapt_commands(&mut watcher.commands, adapter)?;
+        }
+
+        Ok(())
     }
 
-    /// Executes the given command chain.
-    pub(crate) fn execute(&self) -> anyhow::Result<()> {
-        for command in &self.commands {
-            command.execute()?;
+    /// Adapts the positions and images of the chain to the current screen.
+    ///
+    /// Positions bound to outputs are converted to global positions, positions on outputs whose
+    /// size changed since recording are rescaled and images are resized to the current pixel
+    /// density.
+    pub(crate) fn adapt_to_screen(&mut self) -> anyhow::Result<()> {
+        let outputs = match query_outputs() {
+            Ok(outputs) => outputs,
+            Err(err) => {
+                println!("could not determine the outputs, using the recorded positions: {err}");
+                Vec::new()
+            }
+        };
+        let current = Geometry::of_outputs(&outputs);
+
+        if let (Some(recorded), Some(current)) = (&self.geometry, &current) {
+            if recorded != current {
+                println!("adapting the chain recorded on {recorded} to {current}");
+            }
+        }
+
+        self.adapt(&Adapter::new(self.geometry.as_ref(), outputs))?;
+        if current.is_some() {
+            self.geometry = current;
         }
 
         Ok(())
     }
 
+    /// Executes the given command chain with the given initial variables.
+    pub(crate) fn execute(&self, variables: BTreeMap<String, String>) -> anyhow::Result<()> {
+        let mut state = ExecutionState {
+            default_timeout: self.default_timeout,
+            polling: self.polling,
+            variables,
+            watchers: self.watchers.iter().collect(),
+            handling_watcher: false,
+            held_keys: Vec::new(),
+        };
+
+        let result = self.execute_sections(&mut state);
+        let released = state.release_keys();
+
+        result.and(released)
+    }
+
+    /// Executes the setup, the commands and the teardown of the chain.
+    ///
+    /// The teardown runs even if the setup or the commands failed, in which case their error is
+    /// reported and the held keys are released first.
+    fn execute_sections<'a>(&'a self, state: &mut ExecutionState<'a>) -> anyhow::Result<()> {
+        let result = execute_commands(&self.setup, state)
+            .context("failed to set up")
+            .and_then(|()| execute_commands(&self.commands, state));
+        if result.is_err() {
+            if let Err(err) = state.release_keys() {
+                println!("{err:#}");
+            }
+        }
+        let teardown = execute_commands(&self.teardown, state).context("failed to tear down");
+
+        match (result, teardown) {
+            (Err(err), Err(teardown_err)) => {
+                println!("{teardown_err:#}");
+                Err(err)
+            }
+            (result, teardown) => result.and(teardown),
+        }
+    }
+
     /// Converts the command chain to a PDF file.
     pub(crate) fn to_pdf(&self, out_name: impl AsRef<Path>) -> anyhow::Result<()> {
-        let key_codes = crate::key_codes::KeyCodes::new()?;
-
         let tempdir = tempfile::tempdir()?;
-        let img_path = tempdir.path();
-
-        let mut content = String::new();
-
-        let mut img_idx = 0;
-
-        for command in &self.commands {
-            match command {
-                Command::WaitForImage {
-                    location,
-                    image,
-                    click,
-                } => {
-                    let mut path = img_path.to_path_buf();
-                    path.push(format!("{img_idx}.png"));
-                    image.save(&path)?;
-
-                    content.push_str(&format!(
-                        "== wait for{} image at {location}\n#image(\"{img_idx}.png\")\n\n",
-                        if *click { " and click on" } else { "" },
-                    ));
 
-                    img_idx += 1;
+        let mut printer = Printer {
+            key_codes: crate::key_codes::KeyCodes::new()?,
+            img_path: tempdir.path(),
+            img_idx: 0,
+            content: String::new(),
+        };
+
+        if let Some(geometry) = &self.geometry {
+            printer
+                .content
+                .push_str(&format!("recorded on the outputs {geometry}\n\n"));
+        }
+        if let Some(timeout) = self.default_timeout {
+            printer
+                .content
+                .push_str(&format!("default timeout: {timeout:?}\n\n"));
+        }
+
+        printer.print_chain(self, 2)?;
+
+        let mut path = tempdir.path().to_path_buf();
+        path.push("joined.typ");
+
+        std::fs::write(&path, printer.content)?;
+
+        std::process::Command::new("typst")
+            .arg("compile")
+            .arg(path)
+            .arg(out_name.as_ref())
+            .output()?;
+
+        Ok(())
+    }
+}
+
+/// Escapes text so that it is displayed verbatim in typst markup.
+fn typst_str(text: &str) -> String {
+    format!("#{text:?}")
+}
+
+/// Converts commands to typst markup.
+struct Printer<'a> {
+    /// The available key codes.
+    key_codes: crate::key_codes::KeyCodes,
+    /// The directory where images are stored.
+    img_path: &'a Path,
+    /// The index of the next image to store.
+    img_idx: usize,
+    /// The typst markup generated so far.
+    content: String,
+}
+
+impl Printer<'_> {
+    /// Returns the names of the given keys, one per line.
+    fn keys(&self, keys: &[u32]) -> String {
+        keys.iter()
+            .map(|key| {
+                self.key_codes
+                    .reverse_lookup(*key)
+                    .unwrap_or("<unknown key>")
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    /// Saves the image and returns the markup to include it.
+    fn image(&mut self, image: &RgbImage) -> anyhow::Result<String> {
+        let img_idx = self.img_idx;
+        self.img_idx += 1;
+
+        let mut path = self.img_path.to_path_buf();
+        path.push(format!("{img_idx}.png"));
+        image.save(&path)?;
+
+        Ok(format!("#image(\"{img_idx}.png\")"))
+    }
+
+    /// Saves the image of the target and returns the markup describing its details.
+    fn image_target(&mut self, target: &ImageTarget) -> anyhow::Result<String> {
+        let mut markup = String::new();
+        if target.tolerance != Tolerance::EXACT {
+            markup.push_str(&format!("with tolerance {}\n\n", target.tolerance));
+        }
+        if !target.mask.is_empty() {
+            markup.push_str("ignoring the following areas of the image\n");
+            for rect in &target.mask {
+                markup.push_str(&format!("- {rect}\n"));
+            }
+            markup.push('\n');
+        }
+        markup.push_str(&self.image(&target.image)?);
+
+        Ok(markup)
+    }
+
+    /// Returns a summary of the condition and the markup describing its details.
+    fn condition(&mut self, condition: &Condition) -> anyhow::Result<(String, String)> {
+        Ok(match condition {
+            Condition::ImagePresent(target) => (
+                format!("{} is present", target.describe()),
+                self.image_target(target)?,
+            ),
+            Condition::ColorPresent(target) => {
+                (format!("{} is present", target.describe()), String::new())
+            }
+            Condition::ShellSucceeds { command } => (
+                "shell command succeeds".to_string(),
+                format!("```bash\n{command}\n```"),
+            ),
+            Condition::Variable {
+                name,
+                comparison,
+                value,
+            } => (
+                format!(
+                    "variable {} {comparison} {}",
+                    typst_str(name),
+                    typst_str(value)
+                ),
+                String::new(),
+            ),
+            Condition::Not(condition) => {
+                let (summary, details) = self.condition(condition)?;
+                (format!("not ({summary})"), details)
+            }
+        })
+    }
+
+    /// Prints the watchers and commands of the chain with headings of the given level.
+    fn print_chain(&mut self, chain: &CommandChain, level: usize) -> anyhow::Result<()> {
+        self.print_watchers(&chain.watchers, level)?;
+        if chain.setup.is_empty() && chain.teardown.is_empty() {
+            return self.print_commands(&chain.commands, level);
+        }
+
+        let heading = "=".repeat(level);
+        for (name, commands) in [
+            ("setup", &chain.setup),
+            ("commands", &chain.commands),
+            ("teardown", &chain.teardown),
+        ] {
+            if !commands.is_empty() {
+                self.content.push_str(&format!("{heading} {name}\n\n"));
+                self.print_commands(commands, level + 1)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Prints the given watchers with headings of the given level.
+    fn print_watchers(&mut self, watchers: &[ImageBranch], level: usize) -> anyhow::Result<()> {
+        let heading = "=".repeat(level);
+        for watcher in watchers {
+            let image = self.image_target(&watcher.target)?;
+            self.content.push_str(&format!(
+                "{heading} whenever {} appears\n{image}\n\n",
+                watcher.target.describe(),
+            ));
+            self.print_commands(&watcher.commands, level + 1)?;
+        }
+
+        Ok(())
+    }
+
+    /// Prints the given commands with headings of the given level.
+    fn print_commands(&mut self, commands: &[Command], level: usize) -> anyhow::Result<()> {
+        for command in commands {
+            self.print_command(command, level)?;
+        }
+
+        Ok(())
+    }
+
+    /// Prints what happens when a wait times out.
+    fn print_wait(&mut self, wait: &WaitOptions, level: usize) -> anyhow::Result<()> {
+        if let Some(timeout) = wait.timeout {
+            self.content
+                .push_str(&format!("times out after {timeout:?}\n\n"));
+        }
+        match &wait.on_timeout {
+            OnTimeout::Fail => (),
+            OnTimeout::Skip => self.content.push_str("on timeout: skip\n\n"),
+            OnTimeout::Recover(commands) => {
+                self.content.push_str("on timeout: run the following\n\n");
+                self.print_commands(commands, level + 1)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Prints a single command with a heading of the given level.
+    fn print_command(&mut self, command: &Command, level: usize) -> anyhow::Result<()> {
+        let heading = "=".repeat(level);
+
+        match command {
+            Command::WaitForImage {
+                target,
+                click,
+                wait,
+            } => {
+                let image = self.image_target(target)?;
+                self.content.push_str(&format!(
+                    "{heading} wait for{} {}\n{image}\n\n",
+                    click.map_or_else(String::new, |click| format!(" and {click} on")),
+                    target.describe(),
+                ));
+                self.print_wait(wait, level)?;
+            }
+            Command::WaitForImageGone { target, wait } => {
+                let image = self.image_target(target)?;
+                self.content.push_str(&format!(
+                    "{heading} wait for {} to disappear\n{image}\n\n",
+                    target.describe(),
+                ));
+                self.print_wait(wait, level)?;
+            }
+            Command::WaitForAny { branches, wait } => {
+                self.content.push_str(&format!(
+                    "{heading} wait for any of the following images\n\n"
+                ));
+                self.print_wait(wait, level)?;
+                for branch in branches {
+                    let image = self.image_target(&branch.target)?;
+                    self.content.push_str(&format!(
+                        "{heading}= if {} appears\n{image}\n\n",
+                        branch.target.describe(),
+                    ));
+                    self.print_commands(&branch.commands, level + 2)?;
+                }
+            }
+            Command::WaitForColor { target, wait } => {
+                self.content
+                    .push_str(&format!("{heading} wait for {}\n\n", target.describe()));
+                self.print_wait(wait, level)?;
+            }
+            Command::AssertColor { target } => {
+                self.content
+                    .push_str(&format!("{heading} assert {}\n\n", target.describe()));
+            }
+            Command::WaitForText {
+                location,
+                pattern,
+                wait,
+            } => {
+                self.content.push_str(&format!(
+                    "{heading} wait for text {} at {location}\n\n",
+                    typst_str(&pattern.to_string()),
+                ));
+                self.print_wait(wait, level)?;
+            }
+            Command::ExtractText { location, variable } => {
+                self.content.push_str(&format!(
+                    "{heading} extract text at {location} into {}\n\n",
+                    typst_str(variable),
+                ));
+            }
+            Command::If {
+                condition,
+                then,
+                r#else,
+            } => {
+                let (summary, details) = self.condition(condition)?;
+                self.content
+                    .push_str(&format!("{heading} if {summary}\n\n"));
+                if !details.is_empty() {
+                    self.content.push_str(&format!("{details}\n\n"));
                 }
-                Command::Sleep { duration } => {
-                    content.push_str(&format!("== sleep for {duration:?}\n\n"));
+                self.content.push_str(&format!("{heading}= then\n\n"));
+                self.print_commands(then, level + 2)?;
+                if !r#else.is_empty() {
+                    self.content.push_str(&format!("{heading}= else\n\n"));
+                    self.print_commands(r#else, level + 2)?;
                 }
-                Command::Shell { command } => {
-                    content.push_str(&format!(
-                        "== run shell command\n```bash\n{command}\n```\n\n"
-                    ));
+            }
+            Command::Repeat { count, body } => {
+                self.content
+                    .push_str(&format!("{heading} repeat {count} times\n\n"));
+                self.print_commands(body, level + 1)?;
+            }
+            Command::While {
+                condition,
+                body,
+                max_iterations,
+            } => {
+                let (summary, details) = self.condition(condition)?;
+                self.content
+                    .push_str(&format!("{heading} repeat while {summary}\n\n"));
+                if !details.is_empty() {
+                    self.content.push_str(&format!("{details}\n\n"));
+                }
+                self.content
+                    .push_str(&format!("at most {max_iterations} iterations\n\n"));
+                self.print_commands(body, level + 1)?;
+            }
+            Command::Retry {
+                attempts,
+                delay,
+                body,
+                on_failure,
+            } => {
+                self.content.push_str(&format!(
+                    "{heading} attempt up to {} times\n\n",
+                    (*attempts).max(1)
+                ));
+                if !delay.is_zero() {
+                    self.content
+                        .push_str(&format!("waiting {delay:?} between attempts\n\n"));
+                }
+                self.content.push_str(&format!("{heading}= attempt\n\n"));
+                self.print_commands(body, level + 2)?;
+                if !on_failure.is_empty() {
+                    self.content
+                        .push_str(&format!("{heading}= after a failed attempt\n\n"));
+                    self.print_commands(on_failure, level + 2)?;
                 }
-                Command::PressKeys { keys } => {
-                    content.push_str(&format!(
-                        "== pressing keys\n{}\n\n",
-                        keys.iter()
-                            .map(|key| key_codes.reverse_lookup(*key).unwrap_or("<unknown key>"))
-                            .collect::<Vec<_>>()
-                            .join("\n")
+            }
+            Command::Call {
+                path,
+                params,
+                chain,
+            } => {
+                self.content.push_str(&format!(
+                    "{heading} call chain {}\n\n",
+                    typst_str(&path.display().to_string()),
+                ));
+                for (name, value) in params {
+                    self.content.push_str(&format!(
+                        "- with {} set to {}\n",
+                        typst_str(name),
+                        typst_str(value),
                     ));
                 }
-                Command::Type { text } => {
-                    content.push_str(&format!("== type text\n```text\n{text}\n```\n\n"));
+                self.content.push('\n');
+                if let Some(chain) = chain {
+                    self.print_chain(chain, level + 1)?;
                 }
-                Command::Click { position } => {
-                    content.push_str(&format!("== click at {position}\n\n"));
+            }
+            Command::WaitForStable {
+                location,
+                duration,
+                tolerance,
+                wait,
+            } => {
+                self.content.push_str(&format!(
+                    "{heading} wait until {location} is unchanged for {duration:?}\n\n"
+                ));
+                if *tolerance != Tolerance::EXACT {
+                    self.content
+                        .push_str(&format!("with tolerance {tolerance}\n\n"));
+                }
+                self.print_wait(wait, level)?;
+            }
+            Command::Sleep { duration } => {
+                self.content
+                    .push_str(&format!("{heading} sleep for {duration:?}\n\n"));
+            }
+            Command::Shell { command, capture } => {
+                self.content.push_str(&format!(
+                    "{heading} run shell command\n```bash\n{command}\n```\n\n"
+                ));
+                if let Some(Capture {
+                    variable,
+                    json_path,
+                }) = capture
+                {
+                    self.content
+                        .push_str(&format!("storing the output in {}", typst_str(variable)));
+                    if let Some(path) = json_path {
+                        self.content
+                            .push_str(&format!(" from JSON path {}", typst_str(path)));
+                    }
+                    self.content.push_str("\n\n");
                 }
-                Command::MouseMove { position } => {
-                    content.push_str(&format!("== move mouse to {position}\n\n"));
+            }
+            Command::PressKeys { keys } => {
+                let keys = self.keys(keys);
+                self.content
+                    .push_str(&format!("{heading} pressing keys\n{keys}\n\n"));
+            }
+            Command::KeyDown { keys } => {
+                let keys = self.keys(keys);
+                self.content.push_str(&format!(
+                    "{heading} pressing and keeping keys down\n{keys}\n\n"
+                ));
+            }
+            Command::KeyUp { keys } => {
+                let keys = self.keys(keys);
+                self.content
+                    .push_str(&format!("{heading} releasing keys\n{keys}\n\n"));
+            }
+            Command::HoldKeys { keys, duration } => {
+                let keys = self.keys(keys);
+                self.content.push_str(&format!(
+                    "{heading} holding keys for {duration:?}\n{keys}\n\n"
+                ));
+            }
+            Command::Type { text } => {
+                self.content
+                    .push_str(&format!("{heading} type text\n```text\n{text}\n```\n\n"));
+            }
+            Command::Click { position, click } => {
+                self.content
+                    .push_str(&format!("{heading} {click} at {position}\n\n"));
+            }
+            Command::Drag {
+                from,
+                to,
+                button,
+                duration,
+            } => {
+                self.content.push_str(&format!(
+                    "{heading} drag with the {button} button from {from} to {to}\n\n"
+                ));
+                if !duration.is_zero() {
+                    self.content.push_str(&format!("taking {duration:?}\n\n"));
                 }
             }
+            Command::Scroll { position, dx, dy } => {
+                self.content.push_str(&format!(
+                    "{heading} scroll down by {dy} and right by {dx} wheel steps at {position}\n\n"
+                ));
+            }
+            Command::MouseMove { position } => {
+                self.content
+                    .push_str(&format!("{heading} move mouse to {position}\n\n"));
+            }
         }
 
-        let mut path = img_path.to_path_buf();
-        path.push("joined.typ");
+        Ok(())
+    }
+}
 
-        std::fs::write(&path, content)?;
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        std::process::Command::new("typst")
-            .arg("compile")
-            .arg(path)
-            .arg(out_name.as_ref())
-            .output()?;
+    fn variables() -> BTreeMap<String, String> {
+        BTreeMap::from([
+            ("name".to_owned(), "it's $HOME".to_owned()),
+            ("row".to_owned(), "3".to_owned()),
+        ])
+    }
 
-        Ok(())
+    #[test]
+    fn interpolate_replaces_placeholders() {
+        let text = interpolate("row {{row}} of {{ name }}", &variables()).unwrap();
+        assert_eq!(text, "row 3 of it's $HOME");
+    }
+
+    #[test]
+    fn interpolate_fails_for_unset_variables() {
+        let err = interpolate("{{missing}}", &variables()).unwrap_err();
+        assert_eq!(err.to_string(), "variable `missing` is not set");
+    }
+
+    #[test]
+    fn interpolate_keeps_escaped_braces() {
+        let text = interpolate("{{{{row}} is {{row}}", &variables()).unwrap();
+        assert_eq!(text, "{{row}} is 3");
+        let text = interpolate("{{{{{{{{", &variables()).unwrap();
+        assert_eq!(text, "{{{{");
+    }
+
+    #[test]
+    fn shell_commands_get_variables_in_their_environment() {
+        let state = ExecutionState {
+            default_timeout: None,
+            polling: Polling::default(),
+            variables: variables(),
+            watchers: Vec::new(),
+            handling_watcher: false,
+            held_keys: Vec::new(),
+        };
+
+        let (command, mut process) = state
+            .shell(r#"printf '%s|%s' "$name" "row {{row}}""#)
+            .unwrap();
+        assert_eq!(command, r#"printf '%s|%s' "$name" "row 3""#);
+        let output = process.output().unwrap();
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "it's $HOME|row 3");
+    }
+
+    #[test]
+    fn json_lookup_follows_keys_and_indices() {
+        let json = serde_json::json!({ "items": [{ "token": "a" }, { "token": "b" }], "count": 2 });
+        assert_eq!(json_lookup(&json, "items[1].token").unwrap(), "b");
+        assert_eq!(json_lookup(&json, "$.count").unwrap(), 2);
+        assert_eq!(json_lookup(&json, "").unwrap(), &json);
+    }
+
+    #[test]
+    fn json_lookup_fails_for_missing_values() {
+        let json = serde_json::json!({ "items": [] });
+        assert!(json_lookup(&json, "items[0]").is_err());
+        assert!(json_lookup(&json, "other").is_err());
+        assert!(json_lookup(&json, "items[first]").is_err());
+    }
+
+    #[test]
+    fn polling_interval_grows_up_to_the_maximum() {
+        let polling = Polling::default();
+        assert_eq!(polling.first_interval(), Duration::from_millis(100));
+        assert_eq!(
+            polling.next_interval(Duration::from_millis(100)),
+            Duration::from_millis(150)
+        );
+        assert_eq!(
+            polling.next_interval(Duration::from_millis(900)),
+            Duration::from_secs(1)
+        );
+    }
+
+    #[test]
+    fn polling_interval_survives_extreme_values() {
+        let polling = Polling {
+            interval: Duration::ZERO,
+            backoff: 1e300,
+            max_interval: Duration::from_secs(2),
+        };
+        assert_eq!(polling.first_interval(), MIN_POLL_INTERVAL);
+        assert_eq!(
+            polling.next_interval(Duration::from_secs(1)),
+            Duration::from_secs(2)
+        );
+
+        let polling = Polling {
+            interval: Duration::ZERO,
+            backoff: 1.0,
+            max_interval: Duration::ZERO,
+        };
+        assert_eq!(polling.next_interval(Duration::ZERO), MIN_POLL_INTERVAL);
+    }
+
+    /// Writes chains that only call the given other chains into `dir`.
+    fn write_calling_chains(dir: &Path, calls: &[(&str, &[&str])]) {
+        for (name, called) in calls {
+            let commands = called
+                .iter()
+                .map(|called| serde_json::json!({ "Call": { "path": called } }))
+                .collect::<Vec<_>>();
+            let chain = serde_json::json!({ "commands": commands });
+            std::fs::write(dir.join(name), chain.to_string()).unwrap();
+        }
+    }
+
+    #[test]
+    fn load_rejects_cyclic_calls() {
+        let dir = tempfile::tempdir().unwrap();
+        write_calling_chains(
+            dir.path(),
+            &[("a.json", &["b.json"]), ("b.json", &["a.json"])],
+        );
+
+        let Err(err) = CommandChain::load(&dir.path().join("a.json")) else {
+            panic!("loading a cyclic chain succeeded")
+        };
+        assert!(format!("{err:#}").contains("calls itself"));
+    }
+
+    #[test]
+    fn load_accepts_chains_called_from_several_places() {
+        let dir = tempfile::tempdir().unwrap();
+        write_calling_chains(
+            dir.path(),
+            &[
+                ("a.json", &["b.json", "c.json"]),
+                ("b.json", &["d.json"]),
+                ("c.json", &["d.json"]),
+                ("d.json", &[]),
+            ],
+        );
+
+        let chain = CommandChain::load(&dir.path().join("a.json")).unwrap();
+        for command in &chain.commands {
+            let Command::Call {
+                chain: Some(called),
+                ..
+            } = command
+            else {
+                panic!("the called chain was not loaded")
+            };
+            assert!(matches!(
+                called.commands.as_slice(),
+                [Command::Call { chain: Some(_), .. }]
+            ));
+        }
     }
 }
@@ -0,0 +1,145 @@
+//! Handles loading the records of data driven runs.
+
+use std::{collections::BTreeMap, path::Path};
+
+use anyhow::Context as _;
+
+/// The values of a single record, by column name.
+pub(crate) type Record = BTreeMap<String, String>;
+
+/// Loads the records stored in a CSV or JSON Lines file.
+pub(crate) fn load_records(path: &Path) -> anyhow::Result<Vec<Record>> {
+    match path.extension().and_then(|extension| extension.to_str()) {
+        Some("csv") => load_csv(path),
+        Some("jsonl" | "ndjson") => load_json_lines(path),
+        _ => anyhow::bail!(
+            "unsupported data file `{}`, expected a `.csv` or `.jsonl` file",
+            path.display()
+        ),
+    }
+}
+
+/// Loads the records of a CSV file with a header row.
+fn load_csv(path: &Path) -> anyhow::Result<Vec<Record>> {
+    let mut reader = csv::Reader::from_path(path)?;
+    let headers = reader.headers()?.clone();
+
+    reader
+        .records()
+        .enumerate()
+        .map(|(idx, record)| {
+            let record = record.with_context(|| format!("failed to read row {}", idx + 1))?;
+            Ok(headers
+                .iter()
+                .zip(record.iter())
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect())
+        })
+        .collect()
+}
+
+/// Loads the records of a file with one JSON object per line.
+fn load_json_lines(path: &Path) -> anyhow::Result<Vec<Record>> {
+    std::fs::read_to_string(path)?
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .enumerate()
+        .map(|(idx, line)| {
+            let object: serde_json::Map<String, serde_json::Value> = serde_json::from_str(line)
+                .with_context(|| format!("failed to read row {}", idx + 1))?;
+            Ok(object
+                .into_iter()
+                .map(|(name, value)| match value {
+                    serde_json::Value::String(value) => (name, value),
+                    value => (name, value.to_string()),
+                })
+                .collect())
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes `contents` to a temporary file called `name` and loads its records.
+    fn load(name: &str, contents: &str) -> anyhow::Result<Vec<Record>> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join(name);
+        std::fs::write(&path, contents)?;
+        load_records(&path)
+    }
+
+    fn record(values: &[(&str, &str)]) -> Record {
+        values
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn csv_rows_become_records() {
+        let records = load("data.csv", "user,password\nalice,secret\nbob,\"a,b\"\n").unwrap();
+        assert_eq!(
+            records,
+            [
+                record(&[("user", "alice"), ("password", "secret")]),
+                record(&[("user", "bob"), ("password", "a,b")]),
+            ]
+        );
+    }
+
+    #[test]
+    fn csv_with_only_a_header_has_no_records() {
+        assert!(load("data.csv", "user,password\n").unwrap().is_empty());
+    }
+
+    #[test]
+    fn csv_row_with_wrong_column_count_fails() {
+        let err = load("data.csv", "user,password\nalice,secret\nbob\n").unwrap_err();
+        assert_eq!(err.to_string(), "failed to read row 2");
+    }
+
+    #[test]
+    fn json_lines_skip_blank_lines() {
+        let records = load(
+            "data.jsonl",
+            "{\"user\": \"alice\"}\n\n  \n{\"user\": \"bob\"}\n",
+        )
+        .unwrap();
+        assert_eq!(
+            records,
+            [record(&[("user", "alice")]), record(&[("user", "bob")])]
+        );
+    }
+
+    #[test]
+    fn json_lines_convert_other_values_to_json() {
+        let records = load(
+            "data.jsonl",
+            "{\"id\": 5, \"admin\": true, \"name\": null, \"tags\": [\"a\"], \"score\": 1.5}\n",
+        )
+        .unwrap();
+        assert_eq!(
+            records,
+            [record(&[
+                ("id", "5"),
+                ("admin", "true"),
+                ("name", "null"),
+                ("tags", "[\"a\"]"),
+                ("score", "1.5"),
+            ])]
+        );
+    }
+
+    #[test]
+    fn json_lines_that_are_not_objects_fail() {
+        let err = load("data.jsonl", "{\"user\": \"alice\"}\n[1, 2]\n").unwrap_err();
+        assert_eq!(err.to_string(), "failed to read row 2");
+    }
+
+    #[test]
+    fn unknown_extensions_fail() {
+        assert!(load("data.txt", "user\nalice\n").is_err());
+    }
+}
@@ -1,11 +1,16 @@
-use std::{fmt, path::PathBuf};
+use std::{collections::BTreeMap, fmt, path::PathBuf};
 
 use structopt::StructOpt;
 
 mod command;
+mod data;
 mod grim;
 mod key_codes;
+mod matching;
+mod screen;
 mod slurp;
+mod tesseract;
+mod wlr_randr;
 mod ydotool;
 
 /// Kills the wrapped child process on drop.
@@ -62,6 +67,42 @@ impl Rect {
             y: self.y + self.height / 2,
         }
     }
+
+    /// Returns the smallest rectangle containing both rectangles.
+    pub(crate) fn union(self, other: Rect) -> Rect {
+        let x = self.x.min(other.x);
+        let y = self.y.min(other.y);
+
+        Rect {
+            x,
+            y,
+            width: (self.x + self.width).max(other.x + other.width) - x,
+            height: (self.y + self.height).max(other.y + other.height) - y,
+        }
+    }
+
+    /// Returns the overlap of both rectangles, if there is any.
+    pub(crate) fn intersection(self, other: Rect) -> Option<Rect> {
+        let x = self.x.max(other.x);
+        let y = self.y.max(other.y);
+        let right = (self.x + self.width).min(other.x + other.width);
+        let bottom = (self.y + self.height).min(other.y + other.height);
+
+        (x < right && y < bottom).then_some(Rect {
+            x,
+            y,
+            width: right - x,
+            height: bottom - y,
+        })
+    }
+
+    /// Returns whether the other rectangle lies completely within this rectangle.
+    pub(crate) fn contains(self, other: Rect) -> bool {
+        other.x >= self.x
+            && other.y >= self.y
+            && other.x + other.width <= self.x + self.width
+            && other.y + other.height <= self.y + self.height
+    }
 }
 
 impl fmt::Display for Rect {
@@ -76,6 +117,36 @@ impl fmt::Display for Rect {
     }
 }
 
+/// A position or rectangle that is optionally bound to a named output.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub(crate) struct Anchored<T> {
+    /// The coordinates, relative to the output if there is one and global otherwise.
+    #[serde(flatten)]
+    pub(crate) value: T,
+    /// The name of the output the coordinates are relative to.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) output: Option<String>,
+}
+
+impl<T> Anchored<T> {
+    /// Creates global coordinates that are not bound to any output.
+    pub(crate) fn global(value: T) -> Self {
+        Anchored {
+            value,
+            output: None,
+        }
+    }
+}
+
+impl<T: fmt::Display> fmt::Display for Anchored<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.output {
+            Some(output) => write!(f, "{} on {output}", self.value),
+            None => write!(f, "{}", self.value),
+        }
+    }
+}
+
 /// Starts the `ydotoold` process.
 fn start_ydotoold() -> KillOnDrop {
     KillOnDrop(
@@ -90,6 +161,56 @@ fn start_ydotoold() -> KillOnDrop {
     )
 }
 
+/// Parses a variable assignment of the form `name=value`.
+fn parse_var(assignment: &str) -> anyhow::Result<(String, String)> {
+    let Some((name, value)) = assignment.split_once('=') else {
+        anyhow::bail!("expected a variable assignment of the form `name=value`")
+    };
+
+    Ok((name.trim().to_string(), value.to_string()))
+}
+
+/// Executes the chain once for each record from `start_row` on and reports the result of each row.
+fn execute_records(
+    chain: &command::CommandChain,
+    records: &[data::Record],
+    start_row: usize,
+    variables: &BTreeMap<String, String>,
+) -> anyhow::Result<()> {
+    let mut failed_rows = Vec::new();
+    for (idx, record) in records.iter().enumerate().skip(start_row - 1) {
+        let row = idx + 1;
+        println!("Starting row {row}/{}", records.len());
+
+        let mut row_variables = record.clone();
+        row_variables.extend(variables.clone());
+        row_variables.insert("row".to_string(), row.to_string());
+
+        match chain.execute(row_variables) {
+            Ok(()) => println!("row {row} succeeded"),
+            Err(err) => {
+                println!("row {row} failed: {err:#}");
+                failed_rows.push(row);
+            }
+        }
+    }
+
+    if !failed_rows.is_empty() {
+        anyhow::bail!(
+            "{} of {} rows failed: {}",
+            failed_rows.len(),
+            records.len() + 1 - start_row,
+            failed_rows
+                .iter()
+                .map(|row| row.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+    }
+
+    Ok(())
+}
+
 /// lEt Me Do It foR yOu: simple automation on linux
 #[derive(Debug, StructOpt)]
 enum Config {
@@ -112,6 +233,15 @@ enum Config {
         /// the number of runs to perform
         #[structopt(long, short, default_value = "1")]
         num_runs: u32,
+        /// sets a variable for the run, as `name=value`
+        #[structopt(long = "var", number_of_values = 1, parse(try_from_str = parse_var))]
+        vars: Vec<(String, String)>,
+        /// a CSV or JSON Lines file, the chain is executed once per row with the columns as variables
+        #[structopt(long)]
+        data: Option<PathBuf>,
+        /// the row of the data file to start at, to resume an earlier run
+        #[structopt(long, default_value = "1")]
+        start_row: usize,
     },
 }
 
@@ -123,8 +253,7 @@ fn main() -> anyhow::Result<()> {
             commandfile,
             output,
         } => {
-            let chain: command::CommandChain =
-                serde_json::from_reader(std::fs::File::open(commandfile)?)?;
+            let chain = command::CommandChain::load(&commandfile)?;
 
             chain.to_pdf(output)?;
         }
@@ -135,14 +264,34 @@ fn main() -> anyhow::Result<()> {
         Config::Run {
             commandfile,
             num_runs,
+            vars,
+            data,
+            start_row,
         } => {
+            let records = data.as_deref().map(data::load_records).transpose()?;
+            if let Some(records) = &records {
+                if start_row == 0 || start_row > records.len() {
+                    anyhow::bail!(
+                        "the start row must be between 1 and the number of rows ({})",
+                        records.len()
+                    );
+                }
+            }
+
             let _ydotoold = start_ydotoold();
-            let chain: command::CommandChain =
-                serde_json::from_reader(std::fs::File::open(commandfile)?)?;
+            let mut chain = command::CommandChain::load(&commandfile)?;
+            chain.adapt_to_screen()?;
 
             for i in 0..num_runs {
                 println!("Starting run {}/{num_runs}", i + 1);
-                chain.execute()?;
+                let mut variables = vars.iter().cloned().collect::<BTreeMap<_, _>>();
+                variables.insert("run".to_string(), (i + 1).to_string());
+                variables.insert("num_runs".to_string(), num_runs.to_string());
+
+                match &records {
+                    Some(records) => execute_records(&chain, records, start_row, &variables)?,
+                    None => chain.execute(variables)?,
+                }
             }
         }
     }
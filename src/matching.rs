@@ -0,0 +1,423 @@
+//! Handles comparing captured screen contents against reference images.
+
+use std::fmt;
+
+use image::{Rgb, RgbImage};
+
+use crate::Rect;
+
+/// Describes how much a captured image may deviate from a reference image while still matching.
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+pub(crate) struct Tolerance {
+    /// The maximum difference in any color channel for two pixels to be considered equal.
+    pub(crate) channel: u8,
+    /// The maximum fraction of pixels that may differ between the two images.
+    pub(crate) mismatch: f64,
+}
+
+impl Tolerance {
+    /// A tolerance that only accepts identical images.
+    pub(crate) const EXACT: Tolerance = Tolerance {
+        channel: 0,
+        mismatch: 0.0,
+    };
+
+    /// Returns whether the given pixels are considered equal.
+    pub(crate) fn pixel_matches(self, a: Rgb<u8>, b: Rgb<u8>) -> bool {
+        a.0.iter()
+            .zip(b.0.iter())
+            .all(|(a, b)| a.abs_diff(*b) <= self.channel)
+    }
+
+    /// Returns the number of differing pixels that are allowed in an image with `pixels` pixels.
+    fn allowed_mismatches(self, pixels: u64) -> u64 {
+        (self.mismatch.clamp(0.0, 1.0) * pixels as f64).floor() as u64
+    }
+
+    /// Counts the `pixels` of a reference image that differ from `haystack` when placed at
+    /// `(x, y)`.
+    ///
+    /// Counting stops early once more than `limit` differing pixels were found.
+    fn mismatches_at(
+        self,
+        pixels: &[(u32, u32, Rgb<u8>)],
+        haystack: &RgbImage,
+        (x, y): (u32, u32),
+        limit: u64,
+    ) -> u64 {
+        let mut mismatches = 0;
+        for (ref_x, ref_y, pixel) in pixels {
+            if !self.pixel_matches(*pixel, *haystack.get_pixel(x + ref_x, y + ref_y)) {
+                mismatches += 1;
+                if mismatches > limit {
+                    break;
+                }
+            }
+        }
+
+        mismatches
+    }
+
+    /// Returns whether the `candidate` image matches the `reference` image.
+    pub(crate) fn matches(self, reference: &RgbImage, candidate: &RgbImage) -> bool {
+        reference.dimensions() == candidate.dimensions()
+            && self.find(reference, &[], candidate) == Some((0, 0))
+    }
+
+    /// Finds the position within `haystack` where `reference` matches best.
+    ///
+    /// Pixels of `reference` covered by the `mask` are ignored. Returns `None` if there is no
+    /// position where it matches within the tolerance.
+    ///
+    /// Large images are first compared at a lower resolution and the most similar positions are
+    /// compared at full resolution. All other positions are only compared if none of them match.
+    pub(crate) fn find(
+        self,
+        reference: &RgbImage,
+        mask: &[Rect],
+        haystack: &RgbImage,
+    ) -> Option<(u32, u32)> {
+        let (width, height) = reference.dimensions();
+        if width > haystack.width() || height > haystack.height() {
+            return None;
+        }
+        let (max_x, max_y) = (haystack.width() - width, haystack.height() - height);
+        let pixels = unmasked_pixels(reference, mask, 1);
+        let everywhere = Rect {
+            x: 0,
+            y: 0,
+            width: max_x + 1,
+            height: max_y + 1,
+        };
+
+        let Some((factor, positions)) = coarse_positions(reference, mask, haystack) else {
+            return self.best_match(&pixels, haystack, [everywhere]);
+        };
+        let candidates = positions.into_iter().map(|(x, y)| {
+            let (x, y) = (
+                (x * factor).saturating_sub(factor),
+                (y * factor).saturating_sub(factor),
+            );
+            Rect {
+                x,
+                y,
+                width: (x + 3 * factor).min(max_x + 1) - x,
+                height: (y + 3 * factor).min(max_y + 1) - y,
+            }
+        });
+
+        self.best_match(&pixels, haystack, candidates)
+            .or_else(|| self.best_match(&pixels, haystack, [everywhere]))
+    }
+
+    /// Finds the position within the `regions` of `haystack` where the `pixels` of a reference
+    /// image match best.
+    fn best_match(
+        self,
+        pixels: &[(u32, u32, Rgb<u8>)],
+        haystack: &RgbImage,
+        regions: impl IntoIterator<Item = Rect>,
+    ) -> Option<(u32, u32)> {
+        let allowed = self.allowed_mismatches(pixels.len() as u64);
+        let mut best: Option<(u64, (u32, u32))> = None;
+        for region in regions {
+            for y in region.y..region.y + region.height {
+                for x in region.x..region.x + region.width {
+                    let limit = best.map_or(allowed, |(mismatches, _)| mismatches - 1);
+                    let mismatches = self.mismatches_at(pixels, haystack, (x, y), limit);
+                    if mismatches <= limit {
+                        if mismatches == 0 {
+                            return Some((x, y));
+                        }
+                        best = Some((mismatches, (x, y)));
+                    }
+                }
+            }
+        }
+
+        best.map(|(_, position)| position)
+    }
+}
+
+/// The minimum width and height of a reference image that is compared at a lower resolution.
+const MIN_COARSE_SIZE: u32 = 8;
+
+/// The number of positions found at a lower resolution that are compared at full resolution.
+const COARSE_CANDIDATES: usize = 16;
+
+/// Returns the unmasked pixels of `image` that is downscaled by `factor` with their positions.
+///
+/// A downscaled pixel is the average of the pixels it covers and is masked if any of them is.
+fn unmasked_pixels(image: &RgbImage, mask: &[Rect], factor: u32) -> Vec<(u32, u32, Rgb<u8>)> {
+    let (width, height) = (image.width() / factor, image.height() / factor);
+    let mask = mask
+        .iter()
+        .map(|rect| Rect {
+            x: rect.x / factor,
+            y: rect.y / factor,
+            width: (rect.x + rect.width).div_ceil(factor) - rect.x / factor,
+            height: (rect.y + rect.height).div_ceil(factor) - rect.y / factor,
+        })
+        .collect::<Vec<_>>();
+
+    let mut pixels = Vec::new();
+    for y in 0..height {
+        for x in 0..width {
+            if is_masked(&mask, x, y) {
+                continue;
+            }
+            pixels.push((x, y, block_average(image, x * factor, y * factor, factor)));
+        }
+    }
+
+    pixels
+}
+
+/// Returns the average color of the `factor` by `factor` block of pixels at `(x, y)`.
+fn block_average(image: &RgbImage, x: u32, y: u32, factor: u32) -> Rgb<u8> {
+    if factor == 1 {
+        return *image.get_pixel(x, y);
+    }
+
+    let mut sum = [0u32; 3];
+    for block_y in y..y + factor {
+        for block_x in x..x + factor {
+            for (sum, channel) in sum.iter_mut().zip(image.get_pixel(block_x, block_y).0) {
+                *sum += u32::from(channel);
+            }
+        }
+    }
+
+    Rgb(sum.map(|sum| (sum / (factor * factor)) as u8))
+}
+
+/// Finds the positions where the downscaled `reference` is most similar to the downscaled
+/// `haystack`.
+///
+/// Returns the factor both were downscaled by together with the positions in the downscaled
+/// haystack, or `None` if the reference is too small to be downscaled.
+fn coarse_positions(
+    reference: &RgbImage,
+    mask: &[Rect],
+    haystack: &RgbImage,
+) -> Option<(u32, Vec<(u32, u32)>)> {
+    let factor = (reference.width().min(reference.height()) / MIN_COARSE_SIZE).min(4);
+    if factor < 2 {
+        return None;
+    }
+
+    let pixels = unmasked_pixels(reference, mask, factor);
+    if pixels.is_empty() {
+        return None;
+    }
+    let haystack = RgbImage::from_fn(
+        haystack.width() / factor,
+        haystack.height() / factor,
+        |x, y| block_average(haystack, x * factor, y * factor, factor),
+    );
+    let (width, height) = (reference.width() / factor, reference.height() / factor);
+
+    let mut candidates: Vec<(u64, (u32, u32))> = Vec::new();
+    for y in 0..=haystack.height().checked_sub(height)? {
+        for x in 0..=haystack.width().checked_sub(width)? {
+            let limit = match candidates.last() {
+                Some((difference, _)) if candidates.len() == COARSE_CANDIDATES => *difference,
+                _ => u64::MAX,
+            };
+
+            let mut difference = 0;
+            for (ref_x, ref_y, pixel) in &pixels {
+                let other = haystack.get_pixel(x + ref_x, y + ref_y);
+                difference += pixel
+                    .0
+                    .iter()
+                    .zip(other.0)
+                    .map(|(a, b)| u64::from(a.abs_diff(b)))
+                    .sum::<u64>();
+                if difference >= limit {
+                    break;
+                }
+            }
+
+            if difference < limit {
+                let idx = candidates.partition_point(|(other, _)| *other <= difference);
+                candidates.insert(idx, (difference, (x, y)));
+                candidates.truncate(COARSE_CANDIDATES);
+            }
+        }
+    }
+
+    Some((
+        factor,
+        candidates
+            .into_iter()
+            .map(|(_, position)| position)
+            .collect(),
+    ))
+}
+
+/// Returns whether the pixel at `(x, y)` is covered by the mask.
+fn is_masked(mask: &[Rect], x: u32, y: u32) -> bool {
+    mask.iter().any(|rect| {
+        (rect.x..rect.x + rect.width).contains(&x) && (rect.y..rect.y + rect.height).contains(&y)
+    })
+}
+
+impl Default for Tolerance {
+    fn default() -> Self {
+        Self::EXACT
+    }
+}
+
+impl fmt::Display for Tolerance {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Tolerance { channel, mismatch } = self;
+        write!(
+            f,
+            "{{ channel: {channel}, mismatch: {:.1}% }}",
+            mismatch * 100.0
+        )
+    }
+}
+
+/// A color that is stored as a hex string such as `#ff8000`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Color(pub(crate) Rgb<u8>);
+
+impl fmt::Display for Color {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Color(Rgb([r, g, b])) = self;
+        write!(f, "#{r:02x}{g:02x}{b:02x}")
+    }
+}
+
+impl std::str::FromStr for Color {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let hex = s
+            .strip_prefix('#')
+            .filter(|hex| hex.len() == 6 && hex.is_ascii())
+            .ok_or_else(|| anyhow::anyhow!("expected a color of the form `#rrggbb`"))?;
+
+        let channel = |idx: usize| u8::from_str_radix(&hex[idx..idx + 2], 16);
+
+        Ok(Color(Rgb([channel(0)?, channel(2)?, channel(4)?])))
+    }
+}
+
+impl serde::Serialize for Color {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Color {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns a haystack with a varied pattern that does not repeat within small distances.
+    fn haystack() -> RgbImage {
+        RgbImage::from_fn(300, 200, |x, y| {
+            Rgb([
+                (x * 7 + y * 3) as u8,
+                (x * x / 5 + y * 11) as u8,
+                (x ^ (y * 13)) as u8,
+            ])
+        })
+    }
+
+    /// Returns the part of `image` at `(x, y)` with the given size.
+    fn crop(image: &RgbImage, x: u32, y: u32, width: u32, height: u32) -> RgbImage {
+        image::imageops::crop_imm(image, x, y, width, height).to_image()
+    }
+
+    #[test]
+    fn finds_exact_match_at_unaligned_position() {
+        let haystack = haystack();
+        let reference = crop(&haystack, 123, 57, 40, 30);
+        assert_eq!(
+            Tolerance::EXACT.find(&reference, &[], &haystack),
+            Some((123, 57))
+        );
+    }
+
+    #[test]
+    fn finds_unaligned_match_among_repeated_rows() {
+        let mut haystack = RgbImage::from_fn(200, 800, |x, y| {
+            let noise = (x * 73 + (y % 40) * 151).wrapping_mul(2_654_435_761) >> 24;
+            Rgb([noise as u8, (noise >> 1) as u8, (noise * 3) as u8])
+        });
+        for y in 210..214 {
+            for x in 50..54 {
+                haystack.put_pixel(x, y, Rgb([255, 0, 255]));
+            }
+        }
+
+        let reference = crop(&haystack, 33, 203, 64, 32);
+        assert_eq!(
+            Tolerance::EXACT.find(&reference, &[], &haystack),
+            Some((33, 203))
+        );
+    }
+
+    #[test]
+    fn finds_match_within_tolerance() {
+        let haystack = haystack();
+        let mut reference = crop(&haystack, 201, 143, 48, 32);
+        for pixel in reference.pixels_mut() {
+            pixel.0 = pixel.0.map(|channel| channel.saturating_add(3));
+        }
+        for x in 0..10 {
+            reference.put_pixel(x, 5, Rgb([255, 0, 255]));
+        }
+
+        assert_eq!(Tolerance::EXACT.find(&reference, &[], &haystack), None);
+        let tolerance = Tolerance {
+            channel: 3,
+            mismatch: 0.01,
+        };
+        assert_eq!(tolerance.find(&reference, &[], &haystack), Some((201, 143)));
+    }
+
+    #[test]
+    fn ignores_masked_pixels() {
+        let haystack = haystack();
+        let mut reference = crop(&haystack, 17, 101, 32, 32);
+        let mask = Rect {
+            x: 5,
+            y: 6,
+            width: 10,
+            height: 7,
+        };
+        for y in mask.y..mask.y + mask.height {
+            for x in mask.x..mask.x + mask.width {
+                reference.put_pixel(x, y, Rgb([0, 0, 0]));
+            }
+        }
+
+        assert_eq!(Tolerance::EXACT.find(&reference, &[], &haystack), None);
+        assert_eq!(
+            Tolerance::EXACT.find(&reference, &[mask], &haystack),
+            Some((17, 101))
+        );
+    }
+
+    #[test]
+    fn finds_small_reference_without_downscaling() {
+        let haystack = haystack();
+        let reference = crop(&haystack, 299, 7, 1, 5);
+        assert_eq!(
+            Tolerance::EXACT.find(&reference, &[], &haystack),
+            Some((299, 7))
+        );
+    }
+}
@@ -0,0 +1,530 @@
+//! Handles capturing the screen and adapting to its geometry.
+
+use std::fmt;
+
+use image::RgbImage;
+
+use crate::{grim::take_screenshot, wlr_randr::Output, Anchored, Position, Rect};
+
+/// A capture of an area of the screen that is shared between all checks of a polling tick.
+pub(crate) struct Frame {
+    /// The area of the screen that was captured.
+    area: Rect,
+    /// The captured image.
+    image: RgbImage,
+}
+
+impl Frame {
+    /// Captures the smallest area of the screen that covers all of the given areas.
+    pub(crate) fn capture(areas: impl IntoIterator<Item = Rect>) -> anyhow::Result<Option<Self>> {
+        let Some(area) = areas.into_iter().reduce(Rect::union) else { return Ok(None) };
+        let Some(image) = take_screenshot(area)? else { return Ok(None) };
+
+        Ok(Some(Frame { area, image }))
+    }
+
+    /// Returns the number of image pixels per screen coordinate in both directions.
+    fn scale(&self) -> (f64, f64) {
+        (
+            f64::from(self.image.width()) / f64::from(self.area.width),
+            f64::from(self.image.height()) / f64::from(self.area.height),
+        )
+    }
+
+    /// Returns the captured contents of the given area of the screen.
+    ///
+    /// Returns `None` if the area was not captured as part of this frame.
+    pub(crate) fn crop(&self, area: Rect) -> Option<RgbImage> {
+        if !self.area.contains(area) {
+            return None;
+        }
+
+        let (scale_x, scale_y) = self.scale();
+        let x = (f64::from(area.x - self.area.x) * scale_x).round() as u32;
+        let y = (f64::from(area.y - self.area.y) * scale_y).round() as u32;
+        let width = ((f64::from(area.width) * scale_x).round() as u32).min(self.image.width() - x);
+        let height =
+            ((f64::from(area.height) * scale_y).round() as u32).min(self.image.height() - y);
+
+        Some(image::imageops::crop_imm(&self.image, x, y, width, height).to_image())
+    }
+
+    /// Converts a rectangle in pixels within the crop of `area` back to screen coordinates.
+    pub(crate) fn to_screen(
+        &self,
+        area: Rect,
+        (x, y): (u32, u32),
+        (width, height): (u32, u32),
+    ) -> Rect {
+        let (scale_x, scale_y) = self.scale();
+
+        Rect {
+            x: area.x + (f64::from(x) / scale_x).round() as u32,
+            y: area.y + (f64::from(y) / scale_y).round() as u32,
+            width: (f64::from(width) / scale_x).round() as u32,
+            height: (f64::from(height) / scale_y).round() as u32,
+        }
+    }
+}
+
+/// The outputs that make up the screen.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub(crate) struct Geometry {
+    /// The enabled outputs with their logical areas and scale factors.
+    pub(crate) outputs: Vec<Output>,
+}
+
+impl Geometry {
+    /// Computes the geometry of the screen made up of the given outputs.
+    pub(crate) fn of_outputs(outputs: &[Output]) -> Option<Self> {
+        (!outputs.is_empty()).then(|| Geometry {
+            outputs: outputs.to_vec(),
+        })
+    }
+}
+
+impl fmt::Display for Geometry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (idx, output) in self.outputs.iter().enumerate() {
+            if idx > 0 {
+                f.write_str(", ")?;
+            }
+            let Output { name, area, scale } = output;
+            write!(
+                f,
+                "{name} ({}x{} at {},{}, scale {scale})",
+                area.width, area.height, area.x, area.y
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Returns the number of pixels per screen coordinate in screenshots of the given outputs.
+///
+/// grim takes screenshots at the greatest scale factor of all outputs.
+fn pixel_density(outputs: &[Output]) -> Option<f64> {
+    outputs.iter().map(|output| output.scale).reduce(f64::max)
+}
+
+/// Converts coordinates within an output whose logical size changed since recording.
+#[derive(Debug, Clone, Copy)]
+struct Resize {
+    /// The factor by which horizontal coordinates change.
+    x: f64,
+    /// The factor by which vertical coordinates change.
+    y: f64,
+}
+
+impl Resize {
+    /// Creates a conversion between the recorded and the current version of an output, if its
+    /// logical size changed.
+    fn between(recorded: &Output, current: &Output) -> Option<Self> {
+        let (from, to) = (recorded.area, current.area);
+        ((from.width, from.height) != (to.width, to.height)).then(|| Resize {
+            x: f64::from(to.width) / f64::from(from.width),
+            y: f64::from(to.height) / f64::from(from.height),
+        })
+    }
+
+    /// Converts a position relative to the output.
+    fn position(self, Position { x, y }: Position) -> Position {
+        Position {
+            x: (f64::from(x) * self.x).round() as u32,
+            y: (f64::from(y) * self.y).round() as u32,
+        }
+    }
+
+    /// Converts a rectangle relative to the output.
+    fn rect(self, rect: Rect) -> Rect {
+        Rect {
+            x: (f64::from(rect.x) * self.x).round() as u32,
+            y: (f64::from(rect.y) * self.y).round() as u32,
+            width: ((f64::from(rect.width) * self.x).round() as u32).max(1),
+            height: ((f64::from(rect.height) * self.y).round() as u32).max(1),
+        }
+    }
+}
+
+/// Describes how coordinates on a single output are converted to the current screen.
+struct Placement {
+    /// The origin the recorded coordinates are relative to.
+    from: Position,
+    /// The origin the converted coordinates are relative to.
+    to: Position,
+    /// The conversion applied to the relative coordinates, if the output changed its size.
+    resize: Option<Resize>,
+}
+
+impl Placement {
+    /// Converts a position.
+    fn position(&self, position: Position) -> Position {
+        let mut relative = Position {
+            x: position.x - self.from.x,
+            y: position.y - self.from.y,
+        };
+        if let Some(resize) = self.resize {
+            relative = resize.position(relative);
+        }
+
+        Position {
+            x: relative.x + self.to.x,
+            y: relative.y + self.to.y,
+        }
+    }
+
+    /// Converts a rectangle.
+    fn rect(&self, rect: Rect) -> Rect {
+        let Position { x, y } = self.position(rect.origin());
+        let (width, height) = match self.resize {
+            Some(resize) => {
+                let resized = resize.rect(rect);
+                (resized.width, resized.height)
+            }
+            None => (rect.width, rect.height),
+        };
+
+        Rect {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+}
+
+/// Adapts recorded coordinates and images to the current screen.
+///
+/// Only changes of the size of individual outputs move coordinates. Rearranging, adding or
+/// removing outputs leaves global coordinates unchanged, and coordinates bound to an output
+/// follow it to its current position.
+pub(crate) struct Adapter {
+    /// The outputs of the screen the chain was recorded on, if they are known.
+    recorded: Vec<Output>,
+    /// The currently enabled outputs.
+    outputs: Vec<Output>,
+}
+
+impl Adapter {
+    /// Creates an adapter from the recorded geometry to the given current outputs.
+    pub(crate) fn new(recorded: Option<&Geometry>, outputs: Vec<Output>) -> Self {
+        Adapter {
+            recorded: recorded
+                .map(|geometry| geometry.outputs.clone())
+                .unwrap_or_default(),
+            outputs,
+        }
+    }
+
+    /// Creates an adapter for a chain recorded on the given geometry to the same current outputs.
+    pub(crate) fn for_chain(&self, recorded: Option<&Geometry>) -> Self {
+        Adapter::new(recorded, self.outputs.clone())
+    }
+
+    /// Returns the current version of the recorded output.
+    ///
+    /// Outputs are identified by their name, except that a single recorded output corresponds to
+    /// a single current output.
+    fn counterpart(&self, recorded: &Output) -> Option<&Output> {
+        self.outputs
+            .iter()
+            .find(|output| output.name == recorded.name)
+            .or(match (self.recorded.as_slice(), self.outputs.as_slice()) {
+                ([_], [current]) => Some(current),
+                _ => None,
+            })
+    }
+
+    /// Determines how coordinates with the given origin, bound to the given output or global
+    /// otherwise, are converted.
+    fn placement(&self, output: Option<&str>, origin: Position) -> anyhow::Result<Placement> {
+        if let Some(name) = output {
+            let Some(current) = self.outputs.iter().find(|output| output.name == name) else {
+                anyhow::bail!("output `{name}` is not connected")
+            };
+            let recorded = self.recorded.iter().find(|output| output.name == name);
+
+            return Ok(Placement {
+                from: Position { x: 0, y: 0 },
+                to: current.area.origin(),
+                resize: recorded.and_then(|recorded| Resize::between(recorded, current)),
+            });
+        }
+
+        let resized = self
+            .recorded
+            .iter()
+            .find(|recorded| {
+                (recorded.area.x..recorded.area.x + recorded.area.width).contains(&origin.x)
+                    && (recorded.area.y..recorded.area.y + recorded.area.height).contains(&origin.y)
+            })
+            .and_then(|recorded| {
+                let resize = Resize::between(recorded, self.counterpart(recorded)?)?;
+                Some((recorded.area.origin(), resize))
+            });
+
+        Ok(match resized {
+            Some((origin, resize)) => Placement {
+                from: origin,
+                to: origin,
+                resize: Some(resize),
+            },
+            None => Placement {
+                from: Position { x: 0, y: 0 },
+                to: Position { x: 0, y: 0 },
+                resize: None,
+            },
+        })
+    }
+
+    /// Converts a position to global coordinates on the current screen.
+    pub(crate) fn position(&self, position: &mut Anchored<Position>) -> anyhow::Result<()> {
+        let placement = self.placement(position.output.as_deref(), position.value)?;
+        *position = Anchored::global(placement.position(position.value));
+
+        Ok(())
+    }
+
+    /// Converts a rectangle to global coordinates on the current screen.
+    pub(crate) fn rect(&self, rect: &mut Anchored<Rect>) -> anyhow::Result<()> {
+        let placement = self.placement(rect.output.as_deref(), rect.value.origin())?;
+        *rect = Anchored::global(placement.rect(rect.value));
+
+        Ok(())
+    }
+
+    /// Converts rectangles that are relative to the not yet converted `rect`.
+    pub(crate) fn relative_rects(
+        &self,
+        rect: &Anchored<Rect>,
+        relative: &mut [Rect],
+    ) -> anyhow::Result<()> {
+        if let Some(resize) = self
+            .placement(rect.output.as_deref(), rect.value.origin())?
+            .resize
+        {
+            for relative in relative {
+                *relative = resize.rect(*relative);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Resizes an image to the resolution a screenshot of the already converted `location` has.
+    pub(crate) fn image(&self, image: &mut RgbImage, location: Rect) {
+        let Some(density) = pixel_density(&self.outputs) else { return };
+        let width = ((f64::from(location.width) * density).round() as u32).max(1);
+        let height = ((f64::from(location.height) * density).round() as u32).max(1);
+
+        if image.width().abs_diff(width) > 1 || image.height().abs_diff(height) > 1 {
+            *image = image::imageops::resize(
+                image,
+                width,
+                height,
+                image::imageops::FilterType::Triangle,
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Creates an output with the given logical area and scale factor.
+    fn output(name: &str, (x, y): (u32, u32), (width, height): (u32, u32), scale: f64) -> Output {
+        Output {
+            name: name.to_string(),
+            area: Rect {
+                x,
+                y,
+                width,
+                height,
+            },
+            scale,
+        }
+    }
+
+    /// Converts a global position with an adapter from `recorded` to `current`.
+    fn convert(recorded: &[Output], current: &[Output], x: u32, y: u32) -> Position {
+        let adapter = Adapter::new(Geometry::of_outputs(recorded).as_ref(), current.to_vec());
+        let mut position = Anchored::global(Position { x, y });
+        adapter.position(&mut position).unwrap();
+
+        position.value
+    }
+
+    #[test]
+    fn added_output_leaves_positions_and_images_unchanged() {
+        let recorded = [output("DP-1", (0, 0), (1920, 1080), 1.0)];
+        let current = [
+            output("DP-1", (0, 0), (1920, 1080), 1.0),
+            output("HDMI-1", (1920, 0), (1920, 1080), 1.0),
+        ];
+        assert_eq!(
+            convert(&recorded, &current, 1000, 500),
+            Position { x: 1000, y: 500 }
+        );
+
+        let adapter = Adapter::new(Geometry::of_outputs(&recorded).as_ref(), current.to_vec());
+        let mut location = Anchored::global(Rect {
+            x: 1000,
+            y: 500,
+            width: 100,
+            height: 50,
+        });
+        let mut image = RgbImage::new(100, 50);
+        adapter.rect(&mut location).unwrap();
+        adapter.image(&mut image, location.value);
+        assert_eq!(location.value.origin(), Position { x: 1000, y: 500 });
+        assert_eq!(image.dimensions(), (100, 50));
+    }
+
+    #[test]
+    fn changed_scale_only_resizes_images() {
+        let recorded = [output("eDP-1", (0, 0), (1920, 1080), 1.5)];
+        let current = [output("eDP-1", (0, 0), (1920, 1080), 1.0)];
+        assert_eq!(
+            convert(&recorded, &current, 1000, 500),
+            Position { x: 1000, y: 500 }
+        );
+
+        let adapter = Adapter::new(Geometry::of_outputs(&recorded).as_ref(), current.to_vec());
+        let mut image = RgbImage::new(150, 75);
+        adapter.image(
+            &mut image,
+            Rect {
+                x: 1000,
+                y: 500,
+                width: 100,
+                height: 50,
+            },
+        );
+        assert_eq!(image.dimensions(), (100, 50));
+    }
+
+    #[test]
+    fn changed_output_size_rescales_positions_on_that_output() {
+        let recorded = [
+            output("eDP-1", (0, 0), (1280, 720), 1.0),
+            output("DP-1", (1280, 0), (1920, 1080), 1.0),
+        ];
+        let current = [
+            output("eDP-1", (0, 0), (1920, 1080), 1.0),
+            output("DP-1", (1920, 0), (1920, 1080), 1.0),
+        ];
+        assert_eq!(
+            convert(&recorded, &current, 640, 360),
+            Position { x: 960, y: 540 }
+        );
+        assert_eq!(
+            convert(&recorded, &current, 2000, 500),
+            Position { x: 2000, y: 500 }
+        );
+    }
+
+    #[test]
+    fn single_output_corresponds_to_single_output() {
+        let recorded = [output("eDP-1", (0, 0), (1920, 1200), 1.5)];
+        let current = [output("DP-1", (0, 0), (1920, 1080), 1.0)];
+        assert_eq!(
+            convert(&recorded, &current, 1000, 600),
+            Position { x: 1000, y: 540 }
+        );
+    }
+
+    #[test]
+    fn bound_positions_follow_rearranged_outputs() {
+        let recorded = [
+            output("DP-1", (0, 0), (1920, 1080), 1.0),
+            output("HDMI-1", (1920, 0), (1920, 1080), 1.0),
+        ];
+        let current = [
+            output("DP-1", (0, 0), (1920, 1080), 1.0),
+            output("HDMI-1", (0, 1080), (1920, 1080), 1.0),
+        ];
+        let adapter = Adapter::new(Geometry::of_outputs(&recorded).as_ref(), current.to_vec());
+
+        let mut position = Anchored {
+            value: Position { x: 1000, y: 500 },
+            output: Some("HDMI-1".to_string()),
+        };
+        adapter.position(&mut position).unwrap();
+        assert_eq!(position, Anchored::global(Position { x: 1000, y: 1580 }));
+
+        let mut location = Anchored {
+            value: Rect {
+                x: 1000,
+                y: 500,
+                width: 100,
+                height: 50,
+            },
+            output: Some("HDMI-1".to_string()),
+        };
+        let mut image = RgbImage::new(100, 50);
+        adapter.rect(&mut location).unwrap();
+        adapter.image(&mut image, location.value);
+        assert_eq!(
+            location,
+            Anchored::global(Rect {
+                x: 1000,
+                y: 1580,
+                width: 100,
+                height: 50,
+            })
+        );
+        assert_eq!(image.dimensions(), (100, 50));
+    }
+
+    #[test]
+    fn bound_positions_survive_unplugged_outputs() {
+        let recorded = [
+            output("eDP-1", (0, 0), (1920, 1080), 1.0),
+            output("HDMI-1", (1920, 0), (1920, 1080), 1.0),
+        ];
+        let current = [output("HDMI-1", (0, 0), (1920, 1080), 1.0)];
+        let adapter = Adapter::new(Geometry::of_outputs(&recorded).as_ref(), current.to_vec());
+
+        let mut position = Anchored {
+            value: Position { x: 1000, y: 500 },
+            output: Some("HDMI-1".to_string()),
+        };
+        adapter.position(&mut position).unwrap();
+        assert_eq!(position, Anchored::global(Position { x: 1000, y: 500 }));
+
+        let mut unplugged = Anchored {
+            value: Position { x: 1000, y: 500 },
+            output: Some("eDP-1".to_string()),
+        };
+        assert!(adapter.position(&mut unplugged).is_err());
+    }
+
+    #[test]
+    fn bound_positions_are_rescaled_with_their_output() {
+        let recorded = [
+            output("DP-1", (0, 0), (1920, 1080), 1.0),
+            output("HDMI-1", (1920, 0), (1280, 720), 1.0),
+        ];
+        let current = [
+            output("HDMI-1", (0, 0), (1920, 1080), 1.0),
+            output("DP-1", (1920, 0), (1920, 1080), 1.0),
+        ];
+        let adapter = Adapter::new(Geometry::of_outputs(&recorded).as_ref(), current.to_vec());
+
+        let mut position = Anchored {
+            value: Position { x: 640, y: 360 },
+            output: Some("HDMI-1".to_string()),
+        };
+        adapter.position(&mut position).unwrap();
+        assert_eq!(position, Anchored::global(Position { x: 960, y: 540 }));
+
+        let mut position = Anchored {
+            value: Position { x: 640, y: 360 },
+            output: Some("DP-1".to_string()),
+        };
+        adapter.position(&mut position).unwrap();
+        assert_eq!(position, Anchored::global(Position { x: 2560, y: 360 }));
+    }
+}
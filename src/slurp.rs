@@ -6,6 +6,21 @@ pub(crate) fn query_rect(point: bool) -> anyhow::Result<Option<super::Rect>> {
     if point {
         command.arg("-p");
     }
+
+    run(command)
+}
+
+/// Queries the user for a rectangle on the screen, allowing whole outputs to be selected by
+/// clicking on them.
+pub(crate) fn query_area() -> anyhow::Result<Option<super::Rect>> {
+    let mut command = std::process::Command::new("slurp");
+    command.arg("-o");
+
+    run(command)
+}
+
+/// Runs the given slurp command and parses the selected rectangle.
+fn run(mut command: std::process::Command) -> anyhow::Result<Option<super::Rect>> {
     let output = command.output()?;
 
     if !output.status.success() {
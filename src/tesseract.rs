@@ -0,0 +1,30 @@
+//! Handles recognizing text through tesseract.
+
+use std::io::Write as _;
+
+/// Recognizes the text in the given image.
+pub(crate) fn recognize_text(image: &image::RgbImage) -> anyhow::Result<String> {
+    let mut img_buf = std::io::Cursor::new(Vec::new());
+    image.write_to(&mut img_buf, image::ImageOutputFormat::Png)?;
+
+    let mut child = std::process::Command::new("tesseract")
+        .args(["stdin", "stdout"])
+        .args(["--psm", "6"])
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::null())
+        .spawn()?;
+
+    child
+        .stdin
+        .take()
+        .ok_or_else(|| anyhow::anyhow!("failed to open the stdin of tesseract"))?
+        .write_all(&img_buf.into_inner())?;
+
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        anyhow::bail!("tesseract failed with status {}", output.status);
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
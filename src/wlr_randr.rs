@@ -0,0 +1,95 @@
+//! Handles querying the output layout through wlr-randr.
+
+use crate::Rect;
+
+/// A mode of an output as reported by wlr-randr.
+#[derive(serde::Deserialize)]
+struct Mode {
+    /// The horizontal resolution in pixels.
+    width: u32,
+    /// The vertical resolution in pixels.
+    height: u32,
+    /// Whether this is the mode that is currently in use.
+    #[serde(default)]
+    current: bool,
+}
+
+/// A position of an output as reported by wlr-randr.
+#[derive(serde::Deserialize)]
+struct OutputPosition {
+    /// The x position.
+    x: u32,
+    /// The y position.
+    y: u32,
+}
+
+/// An output as reported by wlr-randr.
+#[derive(serde::Deserialize)]
+struct RawOutput {
+    /// The name of the output.
+    name: String,
+    /// Whether the output is enabled.
+    #[serde(default)]
+    enabled: bool,
+    /// The available modes.
+    #[serde(default)]
+    modes: Vec<Mode>,
+    /// The position of the output in the layout.
+    position: Option<OutputPosition>,
+    /// The transform of the output.
+    transform: Option<String>,
+    /// The scale factor of the output.
+    scale: Option<f64>,
+}
+
+/// An enabled output in the current layout.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub(crate) struct Output {
+    /// The name of the output, such as `DP-1`.
+    pub(crate) name: String,
+    /// The area the output covers in the layout.
+    pub(crate) area: Rect,
+    /// The scale factor of the output.
+    pub(crate) scale: f64,
+}
+
+/// Queries the enabled outputs of the current layout.
+pub(crate) fn query_outputs() -> anyhow::Result<Vec<Output>> {
+    let output = std::process::Command::new("wlr-randr")
+        .arg("--json")
+        .stdin(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .output()?;
+
+    if !output.status.success() {
+        anyhow::bail!("wlr-randr failed with status {}", output.status);
+    }
+
+    let raw_outputs: Vec<RawOutput> = serde_json::from_slice(&output.stdout)?;
+
+    Ok(raw_outputs
+        .into_iter()
+        .filter(|output| output.enabled)
+        .filter_map(|output| {
+            let mode = output.modes.iter().find(|mode| mode.current)?;
+            let position = output.position?;
+            let scale = output.scale.unwrap_or(1.0);
+
+            let (width, height) = match output.transform.as_deref() {
+                Some("90" | "270" | "flipped-90" | "flipped-270") => (mode.height, mode.width),
+                _ => (mode.width, mode.height),
+            };
+
+            Some(Output {
+                name: output.name,
+                area: Rect {
+                    x: position.x,
+                    y: position.y,
+                    width: (f64::from(width) / scale).round() as u32,
+                    height: (f64::from(height) / scale).round() as u32,
+                },
+                scale,
+            })
+        })
+        .collect())
+}
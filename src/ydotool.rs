@@ -1,7 +1,107 @@
 //! Handles interaction with the user interface.
 
+use std::{fmt, time::Duration};
+
 use crate::Position;
 
+/// A button of the mouse.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub(crate) enum MouseButton {
+    /// The left mouse button.
+    #[default]
+    Left,
+    /// The right mouse button.
+    Right,
+    /// The middle mouse button.
+    Middle,
+    /// The side mouse button.
+    Side,
+}
+
+impl MouseButton {
+    /// All available mouse buttons.
+    pub(crate) const ALL: &'static [MouseButton] = &[
+        MouseButton::Left,
+        MouseButton::Right,
+        MouseButton::Middle,
+        MouseButton::Side,
+    ];
+
+    /// The code ydotool uses for the button.
+    fn code(self) -> u8 {
+        match self {
+            MouseButton::Left => 0x00,
+            MouseButton::Right => 0x01,
+            MouseButton::Middle => 0x02,
+            MouseButton::Side => 0x03,
+        }
+    }
+
+    /// The code ydotool uses to press the button.
+    fn down(self) -> String {
+        format!("{:x}", 0x40 + self.code())
+    }
+
+    /// The code ydotool uses to release the button.
+    fn up(self) -> String {
+        format!("{:x}", 0x80 + self.code())
+    }
+}
+
+impl fmt::Display for MouseButton {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            MouseButton::Left => "left",
+            MouseButton::Right => "right",
+            MouseButton::Middle => "middle",
+            MouseButton::Side => "side",
+        })
+    }
+}
+
+/// Describes which mouse button is clicked how often.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub(crate) struct MouseClick {
+    /// The button that is clicked.
+    #[serde(default)]
+    pub(crate) button: MouseButton,
+    /// How often the button is clicked, zero is treated as one.
+    #[serde(default = "MouseClick::default_count")]
+    pub(crate) count: u32,
+}
+
+impl MouseClick {
+    /// Returns the default number of clicks.
+    fn default_count() -> u32 {
+        1
+    }
+
+    /// Returns how often the button is actually clicked.
+    fn clicks(self) -> u32 {
+        self.count.max(1)
+    }
+}
+
+impl Default for MouseClick {
+    fn default() -> Self {
+        MouseClick {
+            button: MouseButton::default(),
+            count: MouseClick::default_count(),
+        }
+    }
+}
+
+impl fmt::Display for MouseClick {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (button, count) = (self.button, self.clicks());
+        match count {
+            1 => write!(f, "{button} click"),
+            2 => write!(f, "{button} double click"),
+            _ => write!(f, "{count} {button} clicks"),
+        }
+    }
+}
+
 /// Moves the mouse to the specified position.
 pub(crate) fn move_mouse(Position { x, y }: Position) -> anyhow::Result<()> {
     if !std::process::Command::new("ydotool")
@@ -21,17 +121,38 @@ pub(crate) fn move_mouse(Position { x, y }: Position) -> anyhow::Result<()> {
     Ok(())
 }
 
-/// Clicks on the given position on the screen.
-pub(crate) fn click(position: Position) -> anyhow::Result<()> {
+/// Moves the mouse to the specified position and turns the mouse wheel by the given steps.
+///
+/// Positive steps scroll to the right and down.
+pub(crate) fn scroll(position: Position, dx: i32, dy: i32) -> anyhow::Result<()> {
     move_mouse(position)?;
 
+    if !std::process::Command::new("ydotool")
+        .stdin(std::process::Stdio::null())
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .arg("mousemove")
+        .arg("--wheel")
+        .args(["-x", &format!("{dx}")])
+        // The vertical wheel scrolls up for positive values.
+        .args(["-y", &format!("{}", dy.saturating_neg())])
+        .status()?
+        .success()
+    {
+        anyhow::bail!("ydotool mousemove --wheel failed");
+    }
+
+    Ok(())
+}
+
+/// Presses and releases mouse buttons as described by the given ydotool click codes.
+fn mouse_buttons(codes: impl IntoIterator<Item = String>) -> anyhow::Result<()> {
     if !std::process::Command::new("ydotool")
         .stdin(std::process::Stdio::null())
         .stdout(std::process::Stdio::null())
         .stderr(std::process::Stdio::null())
         .arg("click")
-        .arg("40")
-        .arg("80")
+        .args(codes)
         .status()?
         .success()
     {
@@ -41,15 +162,56 @@ pub(crate) fn click(position: Position) -> anyhow::Result<()> {
     Ok(())
 }
 
-/// Presses the given keys in the given order all at once, then releases them in reverse order.
-pub(crate) fn press_keys(keys: &[u32]) -> anyhow::Result<()> {
+/// Clicks on the given position on the screen.
+pub(crate) fn click(position: Position, click: MouseClick) -> anyhow::Result<()> {
+    move_mouse(position)?;
+
+    let button = click.button;
+    mouse_buttons((0..click.clicks()).flat_map(|_| [button.down(), button.up()]))
+}
+
+/// The time between two mouse movements while dragging.
+const DRAG_STEP: Duration = Duration::from_millis(20);
+
+/// Presses the button at `from`, moves the mouse to `to` over the given duration and releases it.
+///
+/// The button is released even if moving the mouse fails.
+pub(crate) fn drag(
+    from: Position,
+    to: Position,
+    button: MouseButton,
+    duration: Duration,
+) -> anyhow::Result<()> {
+    move_mouse(from)?;
+    mouse_buttons([button.down()])?;
+
+    let steps = (duration.as_secs_f64() / DRAG_STEP.as_secs_f64())
+        .ceil()
+        .max(1.0) as u32;
+    let interpolate = |from: u32, to: u32, step: u32| {
+        (from as f64 + (to as f64 - from as f64) * step as f64 / steps as f64).round() as u32
+    };
+
+    let moved = (1..=steps).try_for_each(|step| {
+        std::thread::sleep(duration / steps);
+        move_mouse(Position {
+            x: interpolate(from.x, to.x, step),
+            y: interpolate(from.y, to.y, step),
+        })
+    });
+
+    mouse_buttons([button.up()])?;
+    moved
+}
+
+/// Sends the given ydotool key events.
+fn key_events(events: impl IntoIterator<Item = String>) -> anyhow::Result<()> {
     if !std::process::Command::new("ydotool")
         .stdin(std::process::Stdio::null())
         .stdout(std::process::Stdio::null())
         .stderr(std::process::Stdio::null())
         .arg("key")
-        .args(keys.iter().map(|key| format!("{key}:1")))
-        .args(keys.iter().rev().map(|key| format!("{key}:0")))
+        .args(events)
         .status()?
         .success()
     {
@@ -59,6 +221,25 @@ pub(crate) fn press_keys(keys: &[u32]) -> anyhow::Result<()> {
     Ok(())
 }
 
+/// Presses the given keys in the given order all at once, then releases them in reverse order.
+pub(crate) fn press_keys(keys: &[u32]) -> anyhow::Result<()> {
+    key_events(
+        keys.iter()
+            .map(|key| format!("{key}:1"))
+            .chain(keys.iter().rev().map(|key| format!("{key}:0"))),
+    )
+}
+
+/// Presses the given keys in the given order without releasing them.
+pub(crate) fn key_down(keys: &[u32]) -> anyhow::Result<()> {
+    key_events(keys.iter().map(|key| format!("{key}:1")))
+}
+
+/// Releases the given keys in the given order.
+pub(crate) fn key_up(keys: &[u32]) -> anyhow::Result<()> {
+    key_events(keys.iter().map(|key| format!("{key}:0")))
+}
+
 /// Types the given text.
 pub(crate) fn r#type(text: &str) -> anyhow::Result<()> {
     if !std::process::Command::new("ydotool")